@@ -0,0 +1,237 @@
+use crate::error::SnakeError;
+use axum::{
+    body::Body,
+    extract::ConnectInfo,
+    http::{HeaderName, Request, Version, header},
+};
+use std::io;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Lines queued for the writer before new entries are dropped
+const QUEUE_CAPACITY: usize = 4096;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+enum Message {
+    Line(String),
+    /// Reopen the file, e.g. after logrotate moved it away
+    Reopen,
+}
+
+/// Handle for appending Combined Log Format lines to the `access_log` file
+#[derive(Clone)]
+pub struct AccessLog {
+    queue: mpsc::Sender<Message>,
+}
+
+/// One request; the response fields are filled in once it has been handled
+pub struct AccessEntry {
+    /// Client IP, `None` for Unix socket connections
+    client_ip: Option<String>,
+    method: String,
+    /// Path and query as sent by the client
+    target: String,
+    version: Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: String,
+    pub status: u16,
+    /// Response body size, `None` when streamed
+    pub bytes: Option<u64>,
+    pub elapsed_ms: u64,
+}
+
+impl AccessLog {
+    /// Open the log file for appending and start the background writer
+    pub async fn open(path: &str) -> Result<Self, SnakeError> {
+        let file = open_file(path).await?;
+        let (queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        spawn_reopen_on_sighup(queue.clone());
+        tokio::spawn(write_lines(path.to_string(), file, receiver));
+        Ok(Self { queue })
+    }
+
+    /// Queue an entry without waiting on the file; entries are dropped while the writer is behind
+    pub fn record(&self, entry: &AccessEntry) {
+        let line = entry.format(SystemTime::now());
+        if self.queue.try_send(Message::Line(line)).is_err() {
+            warn!("Access log queue is full, dropping entry");
+        }
+    }
+}
+
+impl AccessEntry {
+    /// Capture the request line and client details
+    pub fn from_request(req: &Request<Body>, request_id: &str) -> Self {
+        let header = |name: HeaderName| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            client_ip: req
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(peer)| peer.ip().to_string()),
+            method: req.method().to_string(),
+            target: req
+                .uri()
+                .path_and_query()
+                .map_or("/".to_string(), |p| p.to_string()),
+            version: req.version(),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+            request_id: request_id.to_string(),
+            status: 0,
+            bytes: None,
+            elapsed_ms: 0,
+        }
+    }
+
+    /// Combined Log Format, followed by the latency in milliseconds and the request id
+    fn format(&self, time: SystemTime) -> String {
+        format!(
+            "{} - - [{}] \"{} {} {:?}\" {} {} {} {} {} {}\n",
+            self.client_ip.as_deref().unwrap_or("-"),
+            clf_timestamp(time),
+            self.method,
+            escape(&self.target),
+            self.version,
+            self.status,
+            self.bytes.map_or("-".to_string(), |b| b.to_string()),
+            quoted(self.referer.as_deref()),
+            quoted(self.user_agent.as_deref()),
+            self.elapsed_ms,
+            self.request_id
+        )
+    }
+}
+
+async fn open_file(path: &str) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to open access log {}: {}", path, e),
+            )
+        })
+}
+
+async fn write_lines(path: String, file: File, mut queue: mpsc::Receiver<Message>) {
+    let mut writer = BufWriter::new(file);
+    while let Some(message) = queue.recv().await {
+        match message {
+            Message::Line(line) => {
+                if let Err(e) = writer.write_all(line.as_bytes()).await {
+                    error!("Failed to write access log {}: {}", path, e);
+                }
+            }
+            Message::Reopen => {
+                if let Err(e) = writer.flush().await {
+                    error!("Failed to flush access log {}: {}", path, e);
+                }
+                match open_file(&path).await {
+                    Ok(file) => {
+                        writer = BufWriter::new(file);
+                        info!("✓ Access log reopened: {}", path);
+                    }
+                    Err(e) => error!("{}, keeping the current file", e),
+                }
+            }
+        }
+        // Flush once the queue is drained so a burst of requests shares one write
+        if queue.is_empty()
+            && let Err(e) = writer.flush().await
+        {
+            error!("Failed to flush access log {}: {}", path, e);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn spawn_reopen_on_sighup(queue: mpsc::Sender<Message>) {
+    tokio::spawn(async move {
+        let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        {
+            Ok(signal) => signal,
+            Err(e) => {
+                error!(
+                    "Failed to listen for SIGHUP, access log reopen disabled: {}",
+                    e
+                );
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            if queue.send(Message::Reopen).await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn spawn_reopen_on_sighup(_queue: mpsc::Sender<Message>) {}
+
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC
+fn clf_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let seconds_of_day = secs % 86_400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month - 1],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Days since the Unix epoch to (year, month, day), after Howard Hinnant's `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, usize, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as usize, day)
+}
+
+/// Escape quotes and backslashes so a field can't break the line format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn quoted(value: Option<&str>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", escape(value)),
+        None => "\"-\"".to_string(),
+    }
+}
@@ -0,0 +1,79 @@
+use crate::proxy::AppState;
+use axum::{
+    Json,
+    extract::{ConnectInfo, Request, State},
+    http::{Extensions, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use tracing::warn;
+
+/// Marks requests that arrived over the Unix socket, which is gated by its file permissions
+#[derive(Clone, Copy)]
+pub struct UnixSocketClient;
+
+/// Only allow admin routes from loopback clients or the Unix socket.
+/// Requests without a peer address or the Unix socket marker are refused.
+pub async fn local_only(req: Request, next: Next) -> Response {
+    if !is_local(req.extensions()) {
+        warn!("Rejected admin request from non-local address {}", peer_label(req.extensions()));
+        return (StatusCode::FORBIDDEN, "admin endpoints are only available from localhost")
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// Whether a request came from a loopback client or over the Unix socket
+pub fn is_local(extensions: &Extensions) -> bool {
+    extensions.get::<UnixSocketClient>().is_some()
+        || extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(peer)| peer.ip().is_loopback())
+}
+
+/// Peer address for log lines: the address, "unix socket", or "unknown address" without either
+pub fn peer_label(extensions: &Extensions) -> String {
+    match extensions.get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(peer)) => peer.to_string(),
+        None if extensions.get::<UnixSocketClient>().is_some() => "unix socket".to_string(),
+        None => "unknown address".to_string(),
+    }
+}
+
+/// Running per-model cost totals
+pub async fn costs(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.costs.summary())
+}
+
+/// Per-gateway request counters and the current round-robin position
+pub async fn stats(State(state): State<AppState>) -> impl IntoResponse {
+    let state = state.current();
+    let config = &state.config;
+    let gateways: Vec<Value> = config
+        .gateways
+        .iter()
+        .enumerate()
+        .map(|(index, gateway)| {
+            let counters = config.gateway_counters(index);
+            json!({
+                "index": index,
+                "account_id": gateway.account_id,
+                "gateway_id": gateway.gateway_id,
+                "requests": counters.requests.load(Ordering::Relaxed),
+                "successes": counters.successes.load(Ordering::Relaxed),
+                "failures": counters.failures.load(Ordering::Relaxed),
+                "bytes_sent": counters.bytes_sent.load(Ordering::Relaxed),
+                "bytes_received": counters.bytes_received.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "rotation_index": config.rotation_index(),
+        "queue_depth": state.queued.load(Ordering::Relaxed),
+        "gateways": gateways,
+    }))
+}
@@ -0,0 +1,390 @@
+use crate::stream::{CHUNK_DELAY, sse_response};
+use axum::{
+    Json,
+    body::Body,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde_json::{Map, Value, json};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::error;
+
+/// Whether an incoming path is the Anthropic Messages endpoint
+pub fn is_messages_path(path: &str) -> bool {
+    matches!(path, "/v1/messages" | "/messages")
+}
+
+/// Convert an Anthropic Messages request into an OpenAI chat completions request
+pub fn request_to_openai(request: &Value) -> Result<Value, String> {
+    let mut messages = Vec::new();
+    match request.get("system") {
+        Some(Value::String(system)) => {
+            messages.push(json!({"role": "system", "content": system}));
+        }
+        Some(Value::Array(blocks)) => {
+            let text = blocks
+                .iter()
+                .filter_map(|block| block.get("text").and_then(|t| t.as_str()))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            messages.push(json!({"role": "system", "content": text}));
+        }
+        Some(Value::Null) | None => {}
+        Some(_) => {
+            return Err("'system' must be a string or an array of text blocks".to_string());
+        }
+    }
+
+    let input = request
+        .get("messages")
+        .and_then(|m| m.as_array())
+        .ok_or("'messages' must be an array")?;
+    for message in input {
+        let role = message
+            .get("role")
+            .and_then(|r| r.as_str())
+            .ok_or("each message needs a 'role'")?;
+        match message.get("content") {
+            Some(Value::String(text)) => messages.push(json!({"role": role, "content": text})),
+            Some(Value::Array(blocks)) => convert_blocks(role, blocks, &mut messages)?,
+            _ => {
+                return Err("message 'content' must be a string or an array of blocks".to_string());
+            }
+        }
+    }
+
+    let mut converted = Map::new();
+    converted.insert(
+        "model".to_string(),
+        request.get("model").cloned().unwrap_or(Value::Null),
+    );
+    converted.insert("messages".to_string(), Value::Array(messages));
+    for key in ["max_tokens", "temperature", "top_p", "stream"] {
+        if let Some(value) = request.get(key) {
+            converted.insert(key.to_string(), value.clone());
+        }
+    }
+    if let Some(stop) = request.get("stop_sequences") {
+        converted.insert("stop".to_string(), stop.clone());
+    }
+    if let Some(user) = request.pointer("/metadata/user_id") {
+        converted.insert("user".to_string(), user.clone());
+    }
+    if let Some(tools) = request.get("tools").and_then(|t| t.as_array()) {
+        let tools: Vec<Value> = tools
+            .iter()
+            .map(|tool| {
+                let mut function = json!({
+                    "name": tool.get("name"),
+                    "parameters": tool.get("input_schema"),
+                });
+                if let Some(description) = tool.get("description") {
+                    function["description"] = description.clone();
+                }
+                json!({"type": "function", "function": function})
+            })
+            .collect();
+        converted.insert("tools".to_string(), Value::Array(tools));
+    }
+    if let Some(choice) = request.get("tool_choice") {
+        let tool_choice = match choice.get("type").and_then(|t| t.as_str()) {
+            Some("any") => json!("required"),
+            Some("none") => json!("none"),
+            Some("tool") => json!({"type": "function", "function": {"name": choice.get("name")}}),
+            _ => json!("auto"),
+        };
+        converted.insert("tool_choice".to_string(), tool_choice);
+    }
+    Ok(Value::Object(converted))
+}
+
+/// Convert one message's content blocks. Tool results become separate `tool` messages,
+/// tool uses become `tool_calls` on the assistant message.
+fn convert_blocks(role: &str, blocks: &[Value], messages: &mut Vec<Value>) -> Result<(), String> {
+    let mut parts = Vec::new();
+    let mut tool_calls = Vec::new();
+    for block in blocks {
+        match block.get("type").and_then(|t| t.as_str()) {
+            Some("text") => parts.push(json!({"type": "text", "text": block.get("text")})),
+            Some("image") => {
+                let source = block.get("source").ok_or("image block needs a 'source'")?;
+                let field = |key: &str| source.get(key).and_then(|v| v.as_str());
+                let url = match field("type") {
+                    Some("url") => field("url").unwrap_or_default().to_string(),
+                    _ => format!(
+                        "data:{};base64,{}",
+                        field("media_type").unwrap_or("image/png"),
+                        field("data").unwrap_or_default()
+                    ),
+                };
+                parts.push(json!({"type": "image_url", "image_url": {"url": url}}));
+            }
+            Some("tool_use") => {
+                let arguments = block
+                    .get("input")
+                    .map(Value::to_string)
+                    .unwrap_or_else(|| "{}".to_string());
+                tool_calls.push(json!({
+                    "id": block.get("id"),
+                    "type": "function",
+                    "function": {"name": block.get("name"), "arguments": arguments}
+                }));
+            }
+            Some("tool_result") => messages.push(json!({
+                "role": "tool",
+                "tool_call_id": block.get("tool_use_id"),
+                "content": tool_result_text(block.get("content")),
+            })),
+            other => {
+                return Err(format!(
+                    "unsupported content block type: {}",
+                    other.unwrap_or("none")
+                ));
+            }
+        }
+    }
+
+    if parts.is_empty() && tool_calls.is_empty() {
+        return Ok(());
+    }
+    // Plain text stays a string, which every provider accepts
+    let content = match parts.as_slice() {
+        [] => Value::Null,
+        [single] if single["type"] == "text" => single["text"].clone(),
+        _ => Value::Array(parts),
+    };
+    let mut message = json!({"role": role, "content": content});
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = Value::Array(tool_calls);
+    }
+    messages.push(message);
+    Ok(())
+}
+
+/// Tool results may be a string or a list of text blocks
+fn tool_result_text(content: Option<&Value>) -> String {
+    match content {
+        Some(Value::String(text)) => text.clone(),
+        Some(Value::Array(blocks)) => blocks
+            .iter()
+            .filter_map(|b| b.get("text").and_then(|t| t.as_str()))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+/// Convert an OpenAI chat completion into an Anthropic message
+fn response_from_openai(completion: &Value) -> Option<Value> {
+    let choice = completion.get("choices")?.as_array()?.first()?;
+    let message = choice.get("message")?;
+
+    let mut content = Vec::new();
+    if let Some(text) = message.get("content").and_then(|c| c.as_str())
+        && !text.is_empty()
+    {
+        content.push(json!({"type": "text", "text": text}));
+    }
+    if let Some(calls) = message.get("tool_calls").and_then(|c| c.as_array()) {
+        for call in calls {
+            let arguments = call.pointer("/function/arguments").and_then(|a| a.as_str());
+            let input = arguments
+                .and_then(|a| serde_json::from_str::<Value>(a).ok())
+                .unwrap_or_else(|| json!({}));
+            content.push(json!({
+                "type": "tool_use",
+                "id": call.get("id"),
+                "name": call.pointer("/function/name"),
+                "input": input,
+            }));
+        }
+    }
+
+    let stop_reason = match choice.get("finish_reason").and_then(|f| f.as_str()) {
+        Some("length") => "max_tokens",
+        Some("tool_calls") | Some("function_call") => "tool_use",
+        Some("content_filter") => "refusal",
+        _ => "end_turn",
+    };
+    let usage = completion.get("usage");
+    let tokens = |key: &str| {
+        usage
+            .and_then(|u| u.get(key))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0)
+    };
+
+    Some(json!({
+        "id": completion.get("id").cloned().unwrap_or(json!("msg_unknown")),
+        "type": "message",
+        "role": "assistant",
+        "model": completion.get("model").cloned().unwrap_or(json!("unknown")),
+        "content": content,
+        "stop_reason": stop_reason,
+        "stop_sequence": null,
+        "usage": {
+            "input_tokens": tokens("prompt_tokens"),
+            "output_tokens": tokens("completion_tokens"),
+        }
+    }))
+}
+
+/// Anthropic error type for an HTTP status
+fn error_type(status: StatusCode) -> &'static str {
+    match status.as_u16() {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        529 => "overloaded_error",
+        _ => "api_error",
+    }
+}
+
+/// An Anthropic-shaped error response
+fn error_response(status: StatusCode, message: &str) -> Response {
+    let body = json!({
+        "type": "error",
+        "error": {"type": error_type(status), "message": message}
+    });
+    (status, Json(body)).into_response()
+}
+
+/// Translate the upstream chat completions response back to the Messages format,
+/// as a JSON message or, for `stream: true` requests, an Anthropic event stream
+pub fn translate_response(status: StatusCode, body: &[u8], stream: bool) -> Response {
+    let parsed = serde_json::from_slice::<Value>(body).ok();
+
+    if !status.is_success() {
+        let message = parsed
+            .as_ref()
+            .and_then(|json| {
+                json.pointer("/error/message")
+                    .or_else(|| json.get("error"))
+                    .or_else(|| json.get("message"))
+                    .and_then(|m| m.as_str())
+                    .map(str::to_string)
+            })
+            .unwrap_or_else(|| String::from_utf8_lossy(body).trim().to_string());
+        return error_response(status, &message);
+    }
+
+    let Some(message) = parsed.as_ref().and_then(response_from_openai) else {
+        error!("Upstream response could not be translated to an Anthropic message");
+        return error_response(
+            StatusCode::BAD_GATEWAY,
+            "Upstream returned a response that is not a chat completion",
+        );
+    };
+
+    if stream {
+        stream_message(message)
+    } else {
+        (status, Json(message)).into_response()
+    }
+}
+
+/// Format one Anthropic SSE event
+fn event(name: &str, data: Value) -> String {
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+/// `content_block_start` event opening block `index`
+fn block_start(index: usize, content_block: Value) -> String {
+    event(
+        "content_block_start",
+        json!({"type": "content_block_start", "index": index, "content_block": content_block}),
+    )
+}
+
+/// `content_block_delta` event for block `index`
+fn block_delta(index: usize, delta: Value) -> String {
+    event(
+        "content_block_delta",
+        json!({"type": "content_block_delta", "index": index, "delta": delta}),
+    )
+}
+
+/// Replay a complete message as Anthropic streaming events, splitting text into word deltas
+fn stream_message(message: Value) -> Response {
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(100);
+
+    tokio::spawn(async move {
+        let mut start = message.clone();
+        start["content"] = json!([]);
+        start["stop_reason"] = Value::Null;
+        start["usage"]["output_tokens"] = json!(0);
+        if tx
+            .send(Ok(event(
+                "message_start",
+                json!({"type": "message_start", "message": start}),
+            )))
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        let blocks = message["content"].as_array().cloned().unwrap_or_default();
+        for (index, block) in blocks.iter().enumerate() {
+            let mut events = Vec::new();
+            if block["type"] == "tool_use" {
+                let mut opening = block.clone();
+                opening["input"] = json!({});
+                events.push(block_start(index, opening));
+                let partial_json = block["input"].to_string();
+                events.push(block_delta(
+                    index,
+                    json!({"type": "input_json_delta", "partial_json": partial_json}),
+                ));
+            } else {
+                events.push(block_start(index, json!({"type": "text", "text": ""})));
+                let text = block["text"].as_str().unwrap_or_default();
+                let words: Vec<&str> = text.split_whitespace().collect();
+                for (i, word) in words.iter().enumerate() {
+                    let piece = if i < words.len() - 1 {
+                        format!("{} ", word)
+                    } else {
+                        word.to_string()
+                    };
+                    events.push(block_delta(
+                        index,
+                        json!({"type": "text_delta", "text": piece}),
+                    ));
+                }
+            }
+            events.push(event(
+                "content_block_stop",
+                json!({"type": "content_block_stop", "index": index}),
+            ));
+
+            for data in events {
+                if tx.send(Ok(data)).await.is_err() {
+                    return;
+                }
+                tokio::time::sleep(CHUNK_DELAY).await;
+            }
+        }
+
+        let closing = [
+            event(
+                "message_delta",
+                json!({
+                    "type": "message_delta",
+                    "delta": {"stop_reason": message["stop_reason"], "stop_sequence": null},
+                    "usage": {"output_tokens": message["usage"]["output_tokens"]}
+                }),
+            ),
+            event("message_stop", json!({"type": "message_stop"})),
+        ];
+        for data in closing {
+            if tx.send(Ok(data)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    sse_response(StatusCode::OK, Body::from_stream(ReceiverStream::new(rx)))
+}
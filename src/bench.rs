@@ -0,0 +1,230 @@
+use crate::config::{Config, ConfigOverrides};
+use crate::error::SnakeError;
+use crate::proxy::{AppState, passthrough_client, proxy_handler, upstream_client_builder};
+use crate::test::provider_request;
+use axum::Router;
+use clap::ValueEnum;
+use reqwest::Client;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+/// How the benchmark report is printed
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Human,
+    Json,
+}
+
+/// Benchmark settings from the command line
+pub struct BenchOptions {
+    pub provider: String,
+    pub concurrency: usize,
+    pub duration: Duration,
+    /// Start an in-process proxy instead of targeting the one already running
+    pub embedded: bool,
+    pub format: OutputFormat,
+}
+
+/// What one worker saw while the benchmark ran
+#[derive(Default)]
+struct WorkerStats {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+/// Parse durations such as `30s`, `2m`, `500ms` or a bare number of seconds
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number.parse().map_err(|_| {
+        format!(
+            "invalid duration '{}', expected e.g. 30s, 2m or 500ms",
+            value
+        )
+    })?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        _ => Err(format!(
+            "invalid duration unit '{}', expected ms, s or m",
+            unit
+        )),
+    }
+}
+
+/// Drive sustained concurrent load through the proxy and report throughput and latency
+pub async fn run_bench(
+    config_path: &str,
+    overrides: &ConfigOverrides,
+    options: BenchOptions,
+) -> Result<(), SnakeError> {
+    if options.concurrency == 0 {
+        return Err(SnakeError::Config(
+            "--concurrency must be at least 1".to_string(),
+        ));
+    }
+
+    let config = Config::load(config_path, overrides)?;
+    let provider = config.providers.get(&options.provider).ok_or_else(|| {
+        SnakeError::Config(format!(
+            "Provider '{}' not found in config",
+            options.provider
+        ))
+    })?;
+    if provider.api_keys.is_empty() || provider.test_model.is_empty() {
+        return Err(SnakeError::Config(format!(
+            "Provider '{}' has no API keys or test model configured",
+            options.provider
+        )));
+    }
+    let test_model = provider.test_model.clone();
+    let api_keys = provider.api_keys.clone();
+
+    let (bench_url, server_handle) = if options.embedded {
+        let client = upstream_client_builder(&config)?.build()?;
+        let passthrough = passthrough_client(&config)?;
+        let app = Router::new()
+            .route("/{*path}", axum::routing::any(proxy_handler))
+            .with_state(AppState::new(client, passthrough, config.clone()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        let handle = tokio::spawn(async move { axum::serve(listener, app).await });
+        (
+            format!("http://127.0.0.1:{}/v1/chat/completions", port),
+            Some(handle),
+        )
+    } else {
+        let port = config.socket_addr()?.port();
+        (
+            format!(
+                "http://127.0.0.1:{}{}/v1/chat/completions",
+                port, config.route_prefix
+            ),
+            None,
+        )
+    };
+
+    let human = matches!(options.format, OutputFormat::Human);
+    if human {
+        println!(
+            "\n🏁 Benchmarking {} ({}) with {} worker(s) for {:?}",
+            options.provider, test_model, options.concurrency, options.duration
+        );
+        println!("   Target: {}", bench_url);
+    }
+
+    let bench_client = Client::builder()
+        .timeout(Duration::from_secs(30))
+        .pool_max_idle_per_host(options.concurrency)
+        .build()?;
+
+    let started = Instant::now();
+    let deadline = started + options.duration;
+    let workers: Vec<_> = (0..options.concurrency)
+        .map(|worker| {
+            let client = bench_client.clone();
+            let url = bench_url.clone();
+            let model = test_model.clone();
+            let keys = api_keys.clone();
+            tokio::spawn(async move {
+                let mut stats = WorkerStats::default();
+                let mut sent = worker;
+                while Instant::now() < deadline {
+                    let api_key = &keys[sent % keys.len()];
+                    sent += 1;
+                    let request_started = Instant::now();
+                    let ok = match provider_request(&client, &url, &model, api_key)
+                        .send()
+                        .await
+                    {
+                        Ok(response) => {
+                            response.status().is_success() && response.bytes().await.is_ok()
+                        }
+                        Err(_) => false,
+                    };
+                    stats.latencies.push(request_started.elapsed());
+                    if !ok {
+                        stats.errors += 1;
+                    }
+                }
+                stats
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    for worker in workers {
+        let stats = worker
+            .await
+            .map_err(|e| SnakeError::Upstream(format!("Benchmark worker failed: {}", e)))?;
+        latencies.extend(stats.latencies);
+        errors += stats.errors;
+    }
+    let elapsed = started.elapsed();
+    if let Some(handle) = server_handle {
+        handle.abort();
+    }
+
+    latencies.sort();
+    let total = latencies.len();
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let percentile = |p: f64| match total {
+        0 => 0.0,
+        _ => ms(latencies[((p / 100.0 * total as f64).ceil() as usize).clamp(1, total) - 1]),
+    };
+    let mean = match total {
+        0 => 0.0,
+        _ => ms(latencies.iter().sum::<Duration>()) / total as f64,
+    };
+    let rps = total as f64 / elapsed.as_secs_f64();
+    let error_rate = match total {
+        0 => 0.0,
+        _ => errors as f64 / total as f64 * 100.0,
+    };
+
+    match options.format {
+        OutputFormat::Json => {
+            let report = json!({
+                "provider": options.provider,
+                "model": test_model,
+                "concurrency": options.concurrency,
+                "duration_secs": elapsed.as_secs_f64(),
+                "requests": total,
+                "errors": errors,
+                "error_rate_percent": error_rate,
+                "requests_per_second": rps,
+                "latency_ms": {
+                    "mean": mean,
+                    "p50": percentile(50.0),
+                    "p90": percentile(90.0),
+                    "p99": percentile(99.0),
+                    "max": latencies.last().map_or(0.0, |d| ms(*d)),
+                }
+            });
+            println!("{:#}", report);
+        }
+        OutputFormat::Human => {
+            println!("\n━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!("📊 Benchmark Summary");
+            println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+            println!("  Requests: {} in {:.1}s", total, elapsed.as_secs_f64());
+            println!("  Throughput: {:.1} req/s", rps);
+            println!("  Errors: {} ({:.1}%)", errors, error_rate);
+            println!(
+                "  Latency: mean {:.0}ms, p50 {:.0}ms, p90 {:.0}ms, p99 {:.0}ms, max {:.0}ms",
+                mean,
+                percentile(50.0),
+                percentile(90.0),
+                percentile(99.0),
+                latencies.last().map_or(0.0, |d| ms(*d))
+            );
+        }
+    }
+
+    Ok(())
+}
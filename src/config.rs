@@ -1,53 +1,615 @@
+use crate::error::SnakeError;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
 use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use tracing::info;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// `--config` value that reads the TOML from stdin instead of a file
+pub const STDIN_CONFIG: &str = "-";
 
 /// Single gateway configuration
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct GatewayConfig {
     pub account_id: String,
     pub gateway_id: String,
     pub token: String,
+    /// Override for the gateway URL (e.g. a local mock), used verbatim instead of the Cloudflare template
+    #[serde(default)]
+    pub base_url: Option<String>,
+    /// Explicit upstream `Host` header (e.g. a Cloudflare for SaaS hostname) instead of the URL host
+    #[serde(default)]
+    pub host_header: Option<String>,
 }
 
 impl GatewayConfig {
     /// Construct the full Cloudflare AI Gateway URL for this gateway
     pub fn base_url(&self) -> String {
-        format!(
-            "https://gateway.ai.cloudflare.com/v1/{}/{}",
-            self.account_id, self.gateway_id
-        )
+        match &self.base_url {
+            Some(base_url) => base_url.trim_end_matches('/').to_string(),
+            None => format!(
+                "https://gateway.ai.cloudflare.com/v1/{}/{}",
+                self.account_id, self.gateway_id
+            ),
+        }
     }
 }
 
+/// Request counters for one gateway, exposed by /admin/stats
+#[derive(Debug, Default)]
+pub struct GatewayCounters {
+    pub requests: AtomicU64,
+    pub successes: AtomicU64,
+    pub failures: AtomicU64,
+    /// Request body bytes sent to the gateway
+    pub bytes_sent: AtomicU64,
+    /// Response body bytes read from the gateway
+    pub bytes_received: AtomicU64,
+}
+
 /// Provider-specific configuration
 #[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ProviderConfig {
     #[serde(default)]
     pub api_keys: Vec<String>,
     #[serde(default)]
     pub test_model: String,
+    /// Replace the client's Authorization with rotated `api_keys` when proxying
+    #[serde(default)]
+    pub server_managed_keys: bool,
+}
+
+/// Shadow traffic configuration: mirror a share of requests to another gateway
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowConfig {
+    pub gateway_id: String,
+    /// Percentage of requests to mirror (0-100)
+    #[serde(default)]
+    pub percent: f64,
+    /// Account of the shadow gateway, defaults to the primary gateway's account
+    #[serde(default)]
+    pub account_id: Option<String>,
+    /// Token of the shadow gateway, defaults to the primary gateway's token
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+impl ShadowConfig {
+    /// Build the shadow gateway from the primary gateway chosen for a request
+    pub fn gateway_for(&self, primary: &GatewayConfig) -> GatewayConfig {
+        GatewayConfig {
+            account_id: self
+                .account_id
+                .clone()
+                .unwrap_or_else(|| primary.account_id.clone()),
+            gateway_id: self.gateway_id.clone(),
+            token: self.token.clone().unwrap_or_else(|| primary.token.clone()),
+            base_url: None,
+            host_header: None,
+        }
+    }
+}
+
+/// Where a request's provider is read from, selected by `provider_source`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProviderSource {
+    /// Prefix of the `model` field, e.g. `groq` in `groq/llama-3.1-70b`
+    #[default]
+    Model,
+    /// The `X-Provider` request header
+    Header,
+    /// A top-level `provider` field in the JSON body, removed before forwarding
+    Body,
+}
+
+/// Who supplies the `cf-aig-authorization` header sent to the gateway, selected by
+/// `gateway_auth_mode`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GatewayAuthMode {
+    /// Always send the configured gateway token, replacing any header from the client
+    #[default]
+    Override,
+    /// Forward the client's header untouched, using the gateway token only when it is missing
+    Passthrough,
+    /// Forward the client's header and reject requests without one with 401
+    Require,
+}
+
+/// Request hedging configuration: race a delayed duplicate against a second gateway
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HedgingConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// How long to wait for the primary gateway before sending the hedge
+    #[serde(default = "default_hedging_delay_ms")]
+    pub delay_ms: u64,
+}
+
+fn default_hedging_delay_ms() -> u64 {
+    500
+}
+
+/// `[stickiness]` section: send requests of the same session to the same gateway
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StickinessConfig {
+    /// Request header carrying the session id
+    #[serde(default = "default_stickiness_header")]
+    pub header: String,
+}
+
+fn default_stickiness_header() -> String {
+    "X-Session-Id".to_string()
+}
+
+/// `[acme]` section: obtain and renew the HTTPS certificate automatically via TLS-ALPN-01
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// Names the certificate is issued for; each must resolve to this server on port 443
+    #[serde(default)]
+    pub domains: Vec<String>,
+    /// Contact email addresses for expiry notices from the certificate authority
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Directory holding the account key and issued certificates across restarts
+    #[serde(default)]
+    pub cache_dir: String,
+    /// Use the Let's Encrypt staging directory, whose certificates browsers don't trust
+    #[serde(default)]
+    pub staging: bool,
+}
+
+/// `[http_client]` section: connection pool tuning for the shared upstream client.
+/// Defaults match reqwest's own, so omitting the section changes nothing.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpClientConfig {
+    /// Idle connections kept per host (unlimited when unset)
+    #[serde(default)]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long an idle pooled connection is kept before closing
+    #[serde(default = "default_pool_idle_timeout_ms")]
+    pub pool_idle_timeout_ms: u64,
+    /// TCP keepalive interval; 0 disables keepalive
+    #[serde(default = "default_tcp_keepalive_ms")]
+    pub tcp_keepalive_ms: u64,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            pool_max_idle_per_host: None,
+            pool_idle_timeout_ms: default_pool_idle_timeout_ms(),
+            tcp_keepalive_ms: default_tcp_keepalive_ms(),
+        }
+    }
+}
+
+fn default_pool_idle_timeout_ms() -> u64 {
+    90_000
+}
+
+fn default_tcp_keepalive_ms() -> u64 {
+    15_000
+}
+
+/// `[translation]` section: alternative request formats converted to chat completions
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TranslationConfig {
+    /// Accept Anthropic Messages requests on /v1/messages
+    #[serde(default)]
+    pub anthropic: bool,
+}
+
+/// `[dev]` section: local development helpers
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DevConfig {
+    /// Answer JSON requests locally by echoing the last user message instead of calling upstream
+    #[serde(default)]
+    pub echo: bool,
+}
+
+/// `[preflight]` section: how CORS preflight requests are answered locally
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreflightConfig {
+    /// Answer preflights with 204 instead of forwarding them upstream
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Origins allowed to call the proxy; empty allows any origin
+    #[serde(default)]
+    pub allow_origins: Vec<String>,
+    /// How long browsers may cache a preflight result
+    #[serde(default = "default_preflight_max_age_secs")]
+    pub max_age_secs: u64,
+}
+
+impl Default for PreflightConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            allow_origins: Vec::new(),
+            max_age_secs: default_preflight_max_age_secs(),
+        }
+    }
+}
+
+impl PreflightConfig {
+    /// Whether a browser origin may call the proxy
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allow_origins.is_empty()
+            || self
+                .allow_origins
+                .iter()
+                .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_preflight_max_age_secs() -> u64 {
+    600
+}
+
+/// `[transforms.<provider>]` section: request body fields to drop or rename for one provider
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformConfig {
+    /// Top-level fields removed from the request body
+    #[serde(default)]
+    pub strip: Vec<String>,
+    /// Top-level fields moved to a new name (`old = "new"`)
+    #[serde(default)]
+    pub rename: HashMap<String, String>,
+}
+
+impl TransformConfig {
+    /// Apply the rules to a JSON request body, returning whether anything changed
+    pub fn apply(&self, body: &mut serde_json::Value) -> bool {
+        let Some(fields) = body.as_object_mut() else {
+            return false;
+        };
+        let mut changed = false;
+        for field in &self.strip {
+            changed |= fields.remove(field).is_some();
+        }
+        for (from, to) in &self.rename {
+            if let Some(value) = fields.remove(from) {
+                fields.insert(to.clone(), value);
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Per-token prices for a model, used to estimate request cost from the `usage` object
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelPricing {
+    /// Cost per prompt (input) token
+    #[serde(default)]
+    pub input: f64,
+    /// Cost per completion (output) token
+    #[serde(default)]
+    pub output: f64,
+}
+
+/// `[headers]` / `[response_headers]` section: headers to drop and static headers to add
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeadersConfig {
+    /// Header names to remove; a trailing `*` matches a prefix (e.g. `x-internal-*`)
+    #[serde(default)]
+    pub strip: Vec<String>,
+    /// Headers to set on every upstream request
+    #[serde(default)]
+    pub add: HashMap<String, String>,
+}
+
+/// Parsed strip/add rules for request or response headers
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRules {
+    /// Lowercased names or prefixes (ending in `*`)
+    strip: Vec<String>,
+    add: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl HeaderRules {
+    fn from_config(headers: HeadersConfig, section: &str) -> Result<Self, String> {
+        let add = headers
+            .add
+            .into_iter()
+            .map(|(name, value)| {
+                let header_name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("Invalid header name in [{}] add: '{}'", section, name))?;
+                let header_value = HeaderValue::from_str(&value).map_err(|_| {
+                    format!("Invalid value for header '{}' in [{}] add", name, section)
+                })?;
+                Ok((header_name, header_value))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self {
+            strip: headers.strip.iter().map(|s| s.to_ascii_lowercase()).collect(),
+            add,
+        })
+    }
+
+    /// Remove stripped headers, then insert the static ones. Names match case-insensitively.
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        if !self.strip.is_empty() {
+            let stripped: Vec<HeaderName> = headers
+                .keys()
+                .filter(|name| self.strip.iter().any(|rule| header_matches(rule, name.as_str())))
+                .cloned()
+                .collect();
+            for name in stripped {
+                headers.remove(&name);
+            }
+        }
+        self.add_to(headers);
+    }
+
+    /// Insert the static headers only
+    pub fn add_to(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.add {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+/// Whether a key is still in its cooldown; clears the slot once the cooldown has passed
+fn key_quarantined(
+    slot: &Mutex<Option<Instant>>,
+    now: Instant,
+    provider: &str,
+    index: usize,
+) -> bool {
+    let mut until = slot.lock().unwrap_or_else(|e| e.into_inner());
+    match *until {
+        Some(deadline) if deadline > now => true,
+        Some(_) => {
+            *until = None;
+            info!("Re-enabled API key {} for provider '{}'", index + 1, provider);
+            false
+        }
+        None => false,
+    }
+}
+
+/// `[policy]` section: which models clients may request
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Model patterns clients may use; empty allows every model that isn't denied
+    #[serde(default)]
+    pub allowed_models: Vec<String>,
+    /// Model patterns that are always refused, even when also allowed
+    #[serde(default)]
+    pub denied_models: Vec<String>,
+}
+
+impl PolicyConfig {
+    /// Why a model is refused, or `None` when it may be used. Patterns are matched against the
+    /// full `provider/model` name and the name without the provider prefix.
+    pub fn refusal(&self, model: &str) -> Option<String> {
+        let bare = model.split_once('/').map(|(_, name)| name);
+        let matches = |pattern: &String| {
+            glob_matches(pattern, model) || bare.is_some_and(|name| glob_matches(pattern, name))
+        };
+        if let Some(pattern) = self.denied_models.iter().find(|p| matches(p)) {
+            return Some(format!(
+                "Model '{}' is not allowed (matches denied_models pattern '{}')",
+                model, pattern
+            ));
+        }
+        if !self.allowed_models.is_empty() && !self.allowed_models.iter().any(matches) {
+            return Some(format!(
+                "Model '{}' is not allowed (not in allowed_models)",
+                model
+            ));
+        }
+        None
+    }
+}
+
+/// Case-insensitive glob match, where `*` matches any run of characters and `?` any one
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position it was tried at, for backtracking
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p + 1, t));
+            p += 1;
+        } else if let Some((after_star, tried)) = star {
+            p = after_star;
+            t = tried + 1;
+            star = Some((after_star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Match a lowercased header name against a rule, where a trailing `*` is a prefix wildcard
+fn header_matches(rule: &str, name: &str) -> bool {
+    match rule.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == rule,
+    }
 }
 
 /// Complete configuration loaded from config.toml
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct TomlConfig {
+    #[serde(default = "default_bind_address")]
+    pub bind_address: String,
     #[serde(default = "default_port")]
     pub host_port: u16,
     #[serde(default = "default_https_port")]
     pub https_port: u16,
     #[serde(default)]
     pub https_server: bool,
-    #[serde(default = "default_cert_path")]
-    pub tls_cert_path: String,
-    #[serde(default = "default_key_path")]
-    pub tls_key_path: String,
+    #[serde(default)]
+    pub tls_cert_path: Option<String>,
+    #[serde(default)]
+    pub tls_key_path: Option<String>,
+    /// PKCS#12 (.pfx) bundle used instead of the PEM certificate and key
+    #[serde(default)]
+    pub tls_pkcs12_path: Option<String>,
+    #[serde(default)]
+    pub tls_pkcs12_password: Option<String>,
+    #[serde(default)]
+    pub acme: Option<AcmeConfig>,
+    #[serde(default)]
+    pub redirect_http_port: Option<u16>,
+    #[serde(default = "default_cert_expiry_warn_days")]
+    pub cert_expiry_warn_days: u32,
+    #[serde(default)]
+    pub unix_socket: Option<String>,
+    #[serde(default)]
+    pub upstream_client_cert: Option<String>,
+    #[serde(default)]
+    pub upstream_client_key: Option<String>,
+    #[serde(default)]
+    pub upstream_proxy: Option<String>,
+    #[serde(default)]
+    pub http_client: HttpClientConfig,
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    #[serde(default)]
+    pub route_prefix: String,
+    #[serde(default = "default_allowed_paths")]
+    pub allowed_paths: Vec<String>,
+    #[serde(default)]
+    pub max_concurrent_upstream: Option<usize>,
+    #[serde(default)]
+    pub queue_timeout_ms: u64,
+    #[serde(default = "default_api_key_cooldown_secs")]
+    pub api_key_cooldown_secs: u64,
+    #[serde(default)]
+    pub normalize_errors: bool,
+    #[serde(default)]
+    pub skip_connectivity_check: bool,
+    #[serde(default)]
+    pub warmup_connections: bool,
+    #[serde(default)]
+    pub coalesce_requests: bool,
+    #[serde(default)]
+    pub compress_responses: bool,
+    #[serde(default)]
+    pub strict_json: bool,
+    #[serde(default)]
+    pub provider_source: ProviderSource,
+    #[serde(default)]
+    pub gateway_auth_mode: GatewayAuthMode,
+    #[serde(default)]
+    pub access_log: Option<String>,
+    #[serde(default)]
+    pub persist_rotation_state: bool,
+    #[serde(default = "default_rotation_state_path")]
+    pub rotation_state_path: String,
+    #[serde(default)]
+    pub shadow: Option<ShadowConfig>,
+    #[serde(default)]
+    pub hedging: Option<HedgingConfig>,
+    #[serde(default)]
+    pub stickiness: Option<StickinessConfig>,
+    #[serde(default)]
+    pub pricing: HashMap<String, ModelPricing>,
+    /// Model -> models to retry with, in order, when it fails with a 5xx or no response
+    #[serde(default)]
+    pub fallback: HashMap<String, Vec<String>>,
+    /// Incoming request path -> upstream path appended to the gateway URL
+    #[serde(default)]
+    pub compat_paths: HashMap<String, String>,
+    #[serde(default)]
+    pub headers: HeadersConfig,
+    #[serde(default)]
+    pub translation: TranslationConfig,
+    #[serde(default)]
+    pub dev: DevConfig,
+    #[serde(default)]
+    pub preflight: PreflightConfig,
+    #[serde(default)]
+    pub transforms: HashMap<String, TransformConfig>,
+    #[serde(default)]
+    pub defaults: serde_json::Map<String, serde_json::Value>,
+    #[serde(default)]
+    pub max_tokens_limit: Option<u64>,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    #[serde(default)]
+    pub response_headers: HeadersConfig,
     pub gateways: Vec<GatewayConfig>,
     #[serde(default)]
     pub providers: HashMap<String, ProviderConfig>,
+    /// Named overlays selected with --instance
+    #[serde(default)]
+    pub instances: HashMap<String, toml::Table>,
+}
+
+/// Built-in mapping from OpenAI endpoints to Cloudflare's OpenAI-compatible endpoints
+fn default_compat_paths() -> HashMap<String, String> {
+    [
+        ("/v1/chat/completions", "/compat/chat/completions"),
+        ("/chat/completions", "/compat/chat/completions"),
+        ("/v1/embeddings", "/compat/embeddings"),
+        ("/embeddings", "/compat/embeddings"),
+    ]
+    .into_iter()
+    .map(|(from, to)| (from.to_string(), to.to_string()))
+    .collect()
+}
+
+fn default_bind_address() -> String {
+    "0.0.0.0".to_string()
+}
+
+/// Covers the `/v1` endpoints and the unprefixed sources of the built-in path mappings
+fn default_allowed_paths() -> Vec<String> {
+    ["/v1", "/compat", "/chat/completions", "/embeddings"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+fn default_max_body_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_api_key_cooldown_secs() -> u64 {
+    300
+}
+
+fn default_rotation_state_path() -> String {
+    "snake-rotation.state".to_string()
 }
 
 fn default_port() -> u16 {
@@ -58,45 +620,360 @@ fn default_https_port() -> u16 {
     443
 }
 
-fn default_cert_path() -> String {
-    "cert.pem".to_string()
+/// Gateway path for chat completions, used for requests to a bare `/`
+const OPENAI_COMPAT_PATH: &str = "/compat/chat/completions";
+
+const DEFAULT_CERT_PATH: &str = "cert.pem";
+const DEFAULT_KEY_PATH: &str = "key.pem";
+
+fn default_cert_expiry_warn_days() -> u32 {
+    30
+}
+
+/// Read a saved round-robin position; a missing or unreadable file starts from 0
+fn load_rotation_state(path: &str) -> usize {
+    match fs::read_to_string(path) {
+        Ok(contents) => match contents.trim().parse() {
+            Ok(position) => {
+                info!("Restored round-robin position {} from {}", position, path);
+                position
+            }
+            Err(_) => {
+                warn!("Ignoring invalid rotation state in {}: {:?}", path, contents.trim());
+                0
+            }
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => {
+            warn!("Failed to read rotation state {}: {}", path, e);
+            0
+        }
+    }
+}
+
+/// Parse a bind address, accepting IPv6 literals with or without brackets (`::1` or `[::1]`)
+pub fn parse_bind_address(bind_address: &str) -> Result<IpAddr, String> {
+    let trimmed = bind_address.trim();
+    let unbracketed = trimmed
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(trimmed);
+    unbracketed.parse::<IpAddr>().map_err(|e| {
+        format!(
+            "Invalid bind_address '{}': {} (expected an IP address such as 0.0.0.0, 127.0.0.1, :: or [::1])",
+            bind_address, e
+        )
+    })
+}
+
+/// Command-line overrides applied on top of the config file
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    /// Replaces host_port, or https_port when HTTPS is enabled
+    pub port: Option<u16>,
+    /// Name of the `[instances.<name>]` table merged over the base config
+    pub instance: Option<String>,
+    /// Accept gateways repeated with the same account_id, gateway_id and token (traffic weighting)
+    pub allow_duplicate_gateways: bool,
 }
 
-fn default_key_path() -> String {
-    "key.pem".to_string()
+/// Problems found by `Config::issues`: errors make the config unusable, warnings are likely mistakes
+#[derive(Debug, Default)]
+pub struct ConfigIssues {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// Re-read the config with `[instances.<name>]` merged over the top-level settings
+fn merge_instance(content: &str, name: &str) -> Result<TomlConfig, String> {
+    let mut base: toml::Table =
+        toml::from_str(content).map_err(|e| format!("Failed to parse TOML config: {}", e))?;
+    let overlay = base
+        .remove("instances")
+        .and_then(|instances| match instances {
+            toml::Value::Table(mut instances) => instances.remove(name),
+            _ => None,
+        })
+        .and_then(|overlay| match overlay {
+            toml::Value::Table(overlay) => Some(overlay),
+            _ => None,
+        })
+        .ok_or_else(|| format!("Instance '{}' must be a table", name))?;
+
+    merge_tables(&mut base, overlay);
+    toml::Value::Table(base)
+        .try_into()
+        .map_err(|e| format!("Failed to apply instance '{}': {}", name, e))
+}
+
+/// Nested tables merge key by key; any other value (including arrays like gateways) replaces the base
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Where the HTTPS server's certificate and private key are read from
+#[derive(Clone, PartialEq)]
+pub enum TlsSource {
+    /// A PEM certificate chain and a separate PEM private key
+    Pem { cert_path: String, key_path: String },
+    /// A PKCS#12 (.pfx) bundle holding both, with an optional password
+    Pkcs12 { path: String, password: String },
+    /// Provisioned and renewed through ACME, cached in `cache_dir`
+    Acme(AcmeConfig),
+}
+
+impl TlsSource {
+    /// Pick ACME, the PEM pair or the PKCS#12 bundle; configuring more than one is an error
+    fn from_toml(toml_config: &TomlConfig) -> Result<Self, String> {
+        if let Some(acme) = toml_config.acme.as_ref().filter(|acme| acme.enabled) {
+            if toml_config.tls_cert_path.is_some()
+                || toml_config.tls_key_path.is_some()
+                || toml_config.tls_pkcs12_path.is_some()
+            {
+                return Err("[acme] provisions the certificate itself; remove tls_cert_path, \
+                            tls_key_path and tls_pkcs12_path, or disable [acme]"
+                    .to_string());
+            }
+            if !toml_config.https_server {
+                return Err("[acme] enabled requires https_server = true".to_string());
+            }
+            if acme.domains.iter().all(|domain| domain.trim().is_empty()) {
+                return Err("[acme] enabled requires at least one entry in domains".to_string());
+            }
+            if acme.cache_dir.trim().is_empty() {
+                return Err("[acme] enabled requires cache_dir, so certificates survive restarts \
+                            instead of being re-issued each time"
+                    .to_string());
+            }
+            return Ok(TlsSource::Acme(acme.clone()));
+        }
+        match &toml_config.tls_pkcs12_path {
+            Some(path) => {
+                if toml_config.tls_cert_path.is_some() || toml_config.tls_key_path.is_some() {
+                    return Err("tls_pkcs12_path cannot be combined with tls_cert_path or \
+                                tls_key_path; configure either the PEM pair or the PKCS#12 bundle"
+                        .to_string());
+                }
+                Ok(TlsSource::Pkcs12 {
+                    path: path.clone(),
+                    password: toml_config.tls_pkcs12_password.clone().unwrap_or_default(),
+                })
+            }
+            None if toml_config.tls_pkcs12_password.is_some() => {
+                Err("tls_pkcs12_password is set but tls_pkcs12_path is not".to_string())
+            }
+            None => Ok(TlsSource::Pem {
+                cert_path: toml_config
+                    .tls_cert_path
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_CERT_PATH.to_string()),
+                key_path: toml_config
+                    .tls_key_path
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_KEY_PATH.to_string()),
+            }),
+        }
+    }
+
+    /// The file the certificate is read from, used in log and error messages
+    pub fn cert_file(&self) -> &str {
+        match self {
+            TlsSource::Pem { cert_path, .. } => cert_path,
+            TlsSource::Pkcs12 { path, .. } => path,
+            TlsSource::Acme(acme) => &acme.cache_dir,
+        }
+    }
 }
 
 /// Runtime configuration with round-robin state
 #[derive(Clone)]
 pub struct Config {
     pub listen_addr: String,
+    pub bind_address: String,
     pub http_port: u16,
     pub https_port: u16,
     pub https_server: bool,
-    pub tls_cert_path: String,
-    pub tls_key_path: String,
+    pub tls: TlsSource,
+    /// Plain HTTP port that 308-redirects to the HTTPS server
+    pub redirect_http_port: Option<u16>,
+    /// Warn when the TLS certificate expires within this many days
+    pub cert_expiry_warn_days: u32,
+    pub unix_socket: Option<String>,
+    /// Client certificate (PEM) presented to the upstream gateway for mTLS
+    pub upstream_client_cert: Option<String>,
+    /// Private key (PEM) for `upstream_client_cert`
+    pub upstream_client_key: Option<String>,
+    /// Proxy for all upstream traffic (http://, https://, socks5:// or socks5h://)
+    pub upstream_proxy: Option<String>,
+    /// Connection pool tuning for the upstream client
+    pub http_client: HttpClientConfig,
+    /// Largest request body accepted; bigger ones are answered with 413
+    pub max_body_bytes: usize,
+    /// Path the proxy route is mounted under (e.g. `/ai`), empty for the root
+    pub route_prefix: String,
+    /// Path prefixes that are proxied; anything else gets a local 404
+    pub allowed_paths: Vec<String>,
+    /// Upper bound on in-flight upstream requests; extra requests get 503
+    pub max_concurrent_upstream: Option<usize>,
+    /// How long a request waits for a free slot under `max_concurrent_upstream` before its 503
+    pub queue_timeout: Duration,
+    /// How long a server-managed key is skipped after an upstream 401/403
+    pub api_key_cooldown: Duration,
+    pub normalize_errors: bool,
+    /// Start serving without the Cloudflare connectivity check (offline or mock setups)
+    pub skip_connectivity_check: bool,
+    /// Open a pooled connection to every gateway before accepting traffic
+    pub warmup_connections: bool,
+    /// Share one upstream call between identical concurrent `temperature: 0` requests
+    pub coalesce_requests: bool,
+    /// Gzip large non-streaming responses for clients that accept it
+    pub compress_responses: bool,
+    /// Reject chat completions requests (by upstream endpoint) whose body isn't JSON instead
+    /// of forwarding them
+    pub strict_json: bool,
+    /// Where the provider for key rotation and transforms is read from
+    pub provider_source: ProviderSource,
+    /// Whether the gateway token or the client's `cf-aig-authorization` header is sent upstream
+    pub gateway_auth_mode: GatewayAuthMode,
+    /// File that receives one Combined Log Format line per proxied request
+    pub access_log: Option<String>,
+    /// File the round-robin position is saved to, when `persist_rotation_state` is on
+    pub rotation_state_path: Option<String>,
+    pub shadow: Option<ShadowConfig>,
+    pub hedging: Option<HedgingConfig>,
+    /// Header whose value pins a session to one gateway, from `[stickiness]`
+    pub sticky_header: Option<HeaderName>,
+    pub pricing: HashMap<String, ModelPricing>,
+    pub fallback: HashMap<String, Vec<String>>,
     pub gateways: Vec<GatewayConfig>,
     pub providers: HashMap<String, ProviderConfig>,
+    /// Upstream path for requests to the bare `/` path
     pub openai_compat_path: String,
+    /// Incoming request path -> upstream path, built-in defaults merged with `[compat_paths]`
+    pub compat_paths: HashMap<String, String>,
+    /// Header strip/add rules from `[headers]`
+    pub header_rules: HeaderRules,
+    pub translation: TranslationConfig,
+    pub dev: DevConfig,
+    pub preflight: PreflightConfig,
+    /// Request body rules per provider from `[transforms.<provider>]`
+    pub transforms: HashMap<String, TransformConfig>,
+    /// Fields added to JSON request bodies that don't set them, from `[defaults]`
+    pub defaults: serde_json::Map<String, serde_json::Value>,
+    /// Ceiling for the `max_tokens` a request may ask for
+    pub max_tokens_limit: Option<u64>,
+    /// Model allow and deny lists from `[policy]`
+    pub policy: PolicyConfig,
+    /// Header strip/add rules from `[response_headers]`
+    pub response_header_rules: HeaderRules,
     gateway_counter: Arc<AtomicUsize>,
+    /// Per-gateway counters, indexed like `gateways`
+    gateway_stats: Arc<Vec<GatewayCounters>>,
     provider_counters: HashMap<String, Arc<AtomicUsize>>,
+    /// Per-key quarantine deadlines, indexed like each provider's `api_keys`
+    key_quarantine: HashMap<String, Arc<Vec<Mutex<Option<Instant>>>>>,
 }
 
 impl Config {
-    /// Load configuration from config.toml file
-    pub fn from_toml(path: &str) -> Result<Self, String> {
-        info!("Loading configuration from: {}", path);
-
-        let content = fs::read_to_string(path)
-            .map_err(|e| format!("Failed to read config file {}: {}", path, e))?;
-
-        let toml_config: TomlConfig = toml::from_str(&content)
+    /// Parse TOML read from `path` (used in messages), merging `[instances.<name>]` over the base when selected
+    pub(crate) fn from_toml(
+        content: &str,
+        path: &str,
+        instance: Option<&str>,
+    ) -> Result<Self, String> {
+        let mut toml_config: TomlConfig = toml::from_str(content)
             .map_err(|e| format!("Failed to parse TOML config: {}", e))?;
 
+        if let Some(name) = instance {
+            if !toml_config.instances.contains_key(name) {
+                let mut available: Vec<&str> =
+                    toml_config.instances.keys().map(String::as_str).collect();
+                available.sort();
+                return Err(format!(
+                    "Instance '{}' not found in {} (available: {})",
+                    name,
+                    path,
+                    if available.is_empty() {
+                        "none".to_string()
+                    } else {
+                        available.join(", ")
+                    }
+                ));
+            }
+            toml_config = merge_instance(content, name)?;
+            info!("Using instance '{}'", name);
+        } else if !toml_config.instances.is_empty() {
+            info!(
+                "Config defines {} instance(s); using the base config (select one with --instance)",
+                toml_config.instances.len()
+            );
+        }
+
         if toml_config.gateways.is_empty() {
             return Err("At least one gateway configuration is required".to_string());
         }
 
+        let tls = TlsSource::from_toml(&toml_config)?;
+
+        if toml_config.https_server && toml_config.unix_socket.is_some() {
+            return Err(
+                "unix_socket cannot be combined with https_server = true; disable one of them"
+                    .to_string(),
+            );
+        }
+
+        if let Some(redirect_port) = toml_config.redirect_http_port {
+            if !toml_config.https_server {
+                return Err("redirect_http_port requires https_server = true".to_string());
+            }
+            if redirect_port == toml_config.https_port {
+                return Err(format!(
+                    "redirect_http_port ({}) must differ from https_port",
+                    redirect_port
+                ));
+            }
+        }
+
+        let sticky_header = toml_config
+            .stickiness
+            .map(|stickiness| {
+                HeaderName::from_bytes(stickiness.header.as_bytes()).map_err(|_| {
+                    format!("Invalid header name in [stickiness]: '{}'", stickiness.header)
+                })
+            })
+            .transpose()?;
+        let route_prefix = toml_config.route_prefix.trim_end_matches('/').to_string();
+        if !route_prefix.is_empty()
+            && (!route_prefix.starts_with('/') || route_prefix.contains(['{', '}', '*']))
+        {
+            return Err(format!(
+                "route_prefix must be a plain path starting with '/' (e.g. \"/ai\"), got '{}'",
+                toml_config.route_prefix
+            ));
+        }
+
+        if toml_config.max_concurrent_upstream == Some(0) {
+            return Err("max_concurrent_upstream must be greater than 0".to_string());
+        }
+        if toml_config.max_tokens_limit == Some(0) {
+            return Err("max_tokens_limit must be greater than 0".to_string());
+        }
+
+        if toml_config.upstream_client_cert.is_some() != toml_config.upstream_client_key.is_some() {
+            return Err(
+                "upstream_client_cert and upstream_client_key must be set together".to_string(),
+            );
+        }
+
         info!("Loaded {} gateway(s) from config", toml_config.gateways.len());
         for (idx, gateway) in toml_config.gateways.iter().enumerate() {
             info!(
@@ -105,14 +982,54 @@ impl Config {
                 gateway.account_id,
                 gateway.gateway_id
             );
+            if let Some(base_url) = &gateway.base_url {
+                if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+                    return Err(format!(
+                        "Gateway {}: base_url must start with http:// or https://, got '{}'",
+                        idx + 1,
+                        base_url
+                    ));
+                }
+                info!("  Gateway {}: using custom base_url {}", idx + 1, base_url);
+            }
+            if let Some(host) = &gateway.host_header {
+                if host.is_empty() || HeaderValue::from_str(host).is_err() {
+                    return Err(format!(
+                        "Gateway {}: host_header is not a valid header value: '{}'",
+                        idx + 1,
+                        host
+                    ));
+                }
+                info!("  Gateway {}: sending Host: {}", idx + 1, host);
+            }
+        }
+
+        if let Some(shadow) = &toml_config.shadow {
+            if !(0.0..=100.0).contains(&shadow.percent) {
+                return Err(format!(
+                    "shadow.percent must be between 0 and 100, got {}",
+                    shadow.percent
+                ));
+            }
+            info!(
+                "Shadow traffic: {}% of requests mirrored to gateway_id={}",
+                shadow.percent, shadow.gateway_id
+            );
+        }
+
+        if !toml_config.pricing.is_empty() {
+            info!("Pricing configured for {} model(s)", toml_config.pricing.len());
         }
 
         // Initialize provider counters
         let mut provider_counters = HashMap::new();
+        let mut key_quarantine = HashMap::new();
         for (name, provider) in &toml_config.providers {
             if !provider.api_keys.is_empty() {
                 info!("Provider '{}': {} API key(s)", name, provider.api_keys.len());
                 provider_counters.insert(name.clone(), Arc::new(AtomicUsize::new(0)));
+                let slots = provider.api_keys.iter().map(|_| Mutex::new(None)).collect();
+                key_quarantine.insert(name.clone(), Arc::new(slots));
             }
         }
 
@@ -122,51 +1039,663 @@ impl Config {
         } else {
             toml_config.host_port
         };
-        let listen_addr = format!("0.0.0.0:{}", port);
+        let bind_ip = parse_bind_address(&toml_config.bind_address)?;
+        // SocketAddr brackets IPv6 literals, e.g. [::1]:3000
+        let listen_addr = SocketAddr::new(bind_ip, port).to_string();
+
+        let mut allowed_paths = toml_config.allowed_paths;
+        if let Some(path) = allowed_paths.iter().find(|path| !path.starts_with('/')) {
+            return Err(format!(
+                "allowed_paths entries must start with '/', got '{}'",
+                path
+            ));
+        }
+        // Paths mapped explicitly in [compat_paths] are always served
+        allowed_paths.extend(toml_config.compat_paths.keys().cloned());
+
+        let mut compat_paths = default_compat_paths();
+        for (from, to) in toml_config.compat_paths {
+            if !from.starts_with('/') || !to.starts_with('/') {
+                return Err(format!(
+                    "compat_paths entries must be absolute paths, got '{}' = '{}'",
+                    from, to
+                ));
+            }
+            let (from, to) = (normalize_path(&from), normalize_path(&to));
+            info!("Compat path mapping: {} -> {}", from, to);
+            compat_paths.insert(from, to);
+        }
+
+        let header_rules = HeaderRules::from_config(toml_config.headers, "headers")?;
+        let response_header_rules =
+            HeaderRules::from_config(toml_config.response_headers, "response_headers")?;
+
+        let rotation_state_path = toml_config
+            .persist_rotation_state
+            .then_some(toml_config.rotation_state_path);
+        let rotation_start = rotation_state_path
+            .as_deref()
+            .map_or(0, load_rotation_state);
+
+        let gateway_stats = (0..toml_config.gateways.len())
+            .map(|_| GatewayCounters::default())
+            .collect();
 
         Ok(Self {
             listen_addr,
+            bind_address: bind_ip.to_string(),
             http_port: toml_config.host_port,
             https_port: toml_config.https_port,
             https_server: toml_config.https_server,
-            tls_cert_path: toml_config.tls_cert_path,
-            tls_key_path: toml_config.tls_key_path,
+            tls,
+            redirect_http_port: toml_config.redirect_http_port,
+            cert_expiry_warn_days: toml_config.cert_expiry_warn_days,
+            unix_socket: toml_config.unix_socket,
+            upstream_client_cert: toml_config.upstream_client_cert,
+            upstream_client_key: toml_config.upstream_client_key,
+            upstream_proxy: toml_config.upstream_proxy,
+            http_client: toml_config.http_client,
+            max_body_bytes: toml_config.max_body_bytes,
+            route_prefix,
+            allowed_paths,
+            max_concurrent_upstream: toml_config.max_concurrent_upstream,
+            queue_timeout: Duration::from_millis(toml_config.queue_timeout_ms),
+            api_key_cooldown: Duration::from_secs(toml_config.api_key_cooldown_secs),
+            normalize_errors: toml_config.normalize_errors,
+            skip_connectivity_check: toml_config.skip_connectivity_check,
+            warmup_connections: toml_config.warmup_connections,
+            coalesce_requests: toml_config.coalesce_requests,
+            compress_responses: toml_config.compress_responses,
+            strict_json: toml_config.strict_json,
+            provider_source: toml_config.provider_source,
+            gateway_auth_mode: toml_config.gateway_auth_mode,
+            access_log: toml_config.access_log,
+            rotation_state_path,
+            shadow: toml_config.shadow,
+            hedging: toml_config.hedging,
+            sticky_header,
+            pricing: toml_config.pricing,
+            fallback: toml_config.fallback,
             gateways: toml_config.gateways,
             providers: toml_config.providers,
-            openai_compat_path: "/compat/chat/completions".to_string(),
-            gateway_counter: Arc::new(AtomicUsize::new(0)),
+            openai_compat_path: normalize_path(OPENAI_COMPAT_PATH),
+            compat_paths,
+            header_rules,
+            translation: toml_config.translation,
+            dev: toml_config.dev,
+            preflight: toml_config.preflight,
+            transforms: toml_config.transforms,
+            defaults: toml_config.defaults,
+            max_tokens_limit: toml_config.max_tokens_limit,
+            policy: toml_config.policy,
+            response_header_rules,
+            gateway_counter: Arc::new(AtomicUsize::new(rotation_start)),
+            gateway_stats: Arc::new(gateway_stats),
             provider_counters,
+            key_quarantine,
         })
     }
 
-    /// Get the next gateway using round-robin rotation
-    pub fn next_gateway(&self) -> &GatewayConfig {
-        let index = self.gateway_counter.fetch_add(1, Ordering::Relaxed) % self.gateways.len();
-        &self.gateways[index]
+    /// Save the round-robin position to `rotation_state_path` so a restart continues from it
+    pub fn save_rotation_state(&self) {
+        let Some(path) = &self.rotation_state_path else {
+            return;
+        };
+        let position = self.gateway_counter.load(Ordering::Relaxed);
+        // Write a sibling file and rename it, so a crash never leaves a truncated state file
+        let tmp_path = format!("{}.tmp", path);
+        let result = fs::write(&tmp_path, format!("{}\n", position))
+            .and_then(|_| fs::rename(&tmp_path, path));
+        if let Err(e) = result {
+            warn!("Failed to save rotation state to {}: {}", path, e);
+        }
+    }
+
+    /// Current raw round-robin counter, used to skip saves when nothing changed
+    pub fn rotation_position(&self) -> usize {
+        self.gateway_counter.load(Ordering::Relaxed)
+    }
+
+    /// Load configuration from a file (or stdin when `path` is `-`) and apply command-line overrides
+    pub fn load(path: &str, overrides: &ConfigOverrides) -> Result<Self, SnakeError> {
+        if path == STDIN_CONFIG {
+            info!("Loading configuration from stdin");
+            return Self::from_reader(std::io::stdin().lock(), overrides);
+        }
+        info!("Loading configuration from: {}", path);
+        let content = fs::read_to_string(path).map_err(|e| {
+            SnakeError::Config(format!("Failed to read config file {}: {}", path, e))
+        })?;
+        Self::from_content(&content, path, overrides)
+    }
+
+    /// Load configuration from TOML read to the end of `reader` and apply command-line overrides
+    pub fn from_reader(
+        mut reader: impl Read,
+        overrides: &ConfigOverrides,
+    ) -> Result<Self, SnakeError> {
+        let mut content = String::new();
+        reader
+            .read_to_string(&mut content)
+            .map_err(|e| SnakeError::Config(format!("Failed to read config: {}", e)))?;
+        Self::from_content(&content, "stdin", overrides)
+    }
+
+    fn from_content(
+        content: &str,
+        path: &str,
+        overrides: &ConfigOverrides,
+    ) -> Result<Self, SnakeError> {
+        let mut config = Self::from_toml(content, path, overrides.instance.as_deref())
+            .map_err(SnakeError::Config)?;
+        if let Some(port) = overrides.port {
+            config.set_port(port).map_err(SnakeError::Config)?;
+        }
+        // A repeated gateway block doubles that gateway's traffic share, usually by accident
+        for (duplicate, first) in config.duplicate_gateways() {
+            let gateway = &config.gateways[duplicate];
+            let message = format!(
+                "Gateway {} is an exact copy of gateway {} (account_id={}, gateway_id={}, same token)",
+                duplicate + 1,
+                first + 1,
+                gateway.account_id,
+                gateway.gateway_id
+            );
+            if !overrides.allow_duplicate_gateways {
+                return Err(SnakeError::Config(format!(
+                    "{}; remove it, or pass --allow-duplicate-gateways to weight it on purpose",
+                    message
+                )));
+            }
+            warn!("{} [allowed by --allow-duplicate-gateways]", message);
+        }
+        Ok(config)
+    }
+
+    /// `(index, index of the first identical gateway)` for gateways repeated verbatim
+    fn duplicate_gateways(&self) -> Vec<(usize, usize)> {
+        let mut seen = HashMap::new();
+        self.gateways
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, gateway)| {
+                let key = (
+                    gateway.account_id.as_str(),
+                    gateway.gateway_id.as_str(),
+                    gateway.token.as_str(),
+                );
+                seen.insert(key, idx).map(|first| (idx, first))
+            })
+            .collect()
     }
 
-    /// Get the next API key for a specific provider using round-robin rotation
-    pub fn next_api_key(&self, provider: &str) -> Option<String> {
+    /// Parse `listen_addr`, naming the offending value and the expected format on failure
+    pub fn socket_addr(&self) -> Result<SocketAddr, SnakeError> {
+        self.listen_addr.parse().map_err(|e| {
+            SnakeError::Config(format!(
+                "Invalid listen address '{}': {} (expected IP:PORT such as 0.0.0.0:3000 or [::1]:3000, built from bind_address and the port)",
+                self.listen_addr, e
+            ))
+        })
+    }
+
+    /// Override the listening port, recomputing listen_addr
+    fn set_port(&mut self, port: u16) -> Result<(), String> {
+        if self.https_server {
+            self.https_port = port;
+        } else {
+            self.http_port = port;
+        }
+        let bind_ip = parse_bind_address(&self.bind_address)?;
+        self.listen_addr = SocketAddr::new(bind_ip, port).to_string();
+        info!("Port overridden from command line: {}", port);
+        Ok(())
+    }
+
+    /// Carry the round-robin position, counters and key quarantine over from the config being
+    /// replaced, so a reload doesn't reset rotation or stats
+    pub fn inherit_runtime_state(&mut self, previous: &Config) {
+        self.gateway_counter = previous.gateway_counter.clone();
+        let same_gateways = self.gateways.len() == previous.gateways.len()
+            && self
+                .gateways
+                .iter()
+                .zip(&previous.gateways)
+                .all(|(a, b)| a.account_id == b.account_id && a.gateway_id == b.gateway_id);
+        if same_gateways {
+            self.gateway_stats = previous.gateway_stats.clone();
+        }
+        for (name, counter) in &previous.provider_counters {
+            if let Some(slot) = self.provider_counters.get_mut(name) {
+                *slot = counter.clone();
+            }
+        }
+        // Quarantine slots are indexed like api_keys, so only keep them for unchanged key lists
+        for (name, quarantine) in &previous.key_quarantine {
+            let same_keys = self.providers.get(name).map(|p| &p.api_keys)
+                == previous.providers.get(name).map(|p| &p.api_keys);
+            if same_keys && let Some(slot) = self.key_quarantine.get_mut(name) {
+                *slot = quarantine.clone();
+            }
+        }
+    }
+
+    /// Settings that differ from `other` but only take effect after a restart
+    pub fn restart_only_changes(&self, other: &Config) -> Vec<&'static str> {
+        let checks = [
+            ("listen address", self.listen_addr != other.listen_addr),
+            ("https_server", self.https_server != other.https_server),
+            ("TLS certificate source", self.tls != other.tls),
+            ("redirect_http_port", self.redirect_http_port != other.redirect_http_port),
+            ("unix_socket", self.unix_socket != other.unix_socket),
+            (
+                "upstream client certificate",
+                self.upstream_client_cert != other.upstream_client_cert
+                    || self.upstream_client_key != other.upstream_client_key,
+            ),
+            ("upstream_proxy", self.upstream_proxy != other.upstream_proxy),
+            ("[http_client]", self.http_client != other.http_client),
+            ("route_prefix", self.route_prefix != other.route_prefix),
+            (
+                "max_concurrent_upstream",
+                self.max_concurrent_upstream != other.max_concurrent_upstream,
+            ),
+            ("access_log", self.access_log != other.access_log),
+            ("rotation_state_path", self.rotation_state_path != other.rotation_state_path),
+        ];
+        checks
+            .into_iter()
+            .filter_map(|(name, changed)| changed.then_some(name))
+            .collect()
+    }
+
+    /// Look for misconfigurations that parse fine but break at runtime
+    pub fn issues(&self) -> ConfigIssues {
+        let mut issues = ConfigIssues::default();
+
+        let mut seen = HashMap::new();
+        for (idx, gateway) in self.gateways.iter().enumerate() {
+            if gateway.token.trim().is_empty() {
+                issues
+                    .errors
+                    .push(format!("Gateway {} ({}) has an empty token", idx + 1, gateway.gateway_id));
+            }
+            let key = (gateway.account_id.as_str(), gateway.gateway_id.as_str());
+            if let Some(first) = seen.insert(key, idx + 1) {
+                issues.warnings.push(format!(
+                    "Gateway {} duplicates gateway {} (account_id={}, gateway_id={})",
+                    idx + 1,
+                    first,
+                    gateway.account_id,
+                    gateway.gateway_id
+                ));
+            }
+        }
+
+        let mut provider_names: Vec<&String> = self.providers.keys().collect();
+        provider_names.sort();
+        for name in provider_names {
+            let provider = &self.providers[name];
+            if provider.api_keys.iter().any(|key| key.trim().is_empty()) {
+                issues
+                    .errors
+                    .push(format!("Provider '{}' has an empty entry in api_keys", name));
+            }
+            if provider.api_keys.is_empty() && !provider.test_model.is_empty() {
+                issues.warnings.push(format!(
+                    "Provider '{}' has test_model '{}' but no api_keys, so `snake test` cannot test it",
+                    name, provider.test_model
+                ));
+            }
+        }
+
+        issues
+    }
+
+    /// Get the index of the next gateway using round-robin rotation, `None` when there are no
+    /// gateways to pick from
+    pub fn next_gateway_index(&self) -> Option<usize> {
+        self.gateway_counter
+            .fetch_add(1, Ordering::Relaxed)
+            .checked_rem(self.gateways.len())
+    }
+
+    /// Find a gateway by `gateway_id`, or by its index as listed in /admin/stats
+    pub fn find_gateway(&self, reference: &str) -> Option<usize> {
+        self.gateways
+            .iter()
+            .position(|gateway| gateway.gateway_id == reference)
+            .or_else(|| {
+                reference
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&index| index < self.gateways.len())
+            })
+    }
+
+    /// Index of the gateway the next request will be sent to
+    pub fn rotation_index(&self) -> Option<usize> {
+        self.gateway_counter
+            .load(Ordering::Relaxed)
+            .checked_rem(self.gateways.len())
+    }
+
+    /// Counters for the gateway at `index`
+    pub fn gateway_counters(&self, index: usize) -> &GatewayCounters {
+        &self.gateway_stats[index]
+    }
+
+    /// Count a request sent to the gateway at `index`
+    pub fn record_gateway_request(&self, index: usize, body_bytes: usize) {
+        let counters = &self.gateway_stats[index];
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters
+            .bytes_sent
+            .fetch_add(body_bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count a response body read from the gateway at `index`
+    pub fn record_gateway_response_bytes(&self, index: usize, body_bytes: usize) {
+        self.gateway_stats[index]
+            .bytes_received
+            .fetch_add(body_bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Count the outcome of a request to the gateway at `index`; non-2xx responses are failures
+    pub fn record_gateway_result(&self, index: usize, success: bool) {
+        let counters = &self.gateway_stats[index];
+        let counter = if success {
+            &counters.successes
+        } else {
+            &counters.failures
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Get the next API key (and its index) for a provider with `server_managed_keys` using
+    /// round-robin rotation, skipping quarantined keys. If every key is quarantined the
+    /// plain rotation is used rather than failing the request.
+    pub fn next_api_key(&self, provider: &str) -> Option<(usize, String)> {
         let provider_config = self.providers.get(provider)?;
-        if provider_config.api_keys.is_empty() {
+        if !provider_config.server_managed_keys || provider_config.api_keys.is_empty() {
             return None;
         }
 
         let counter = self.provider_counters.get(provider)?;
-        let index = counter.fetch_add(1, Ordering::Relaxed) % provider_config.api_keys.len();
-        Some(provider_config.api_keys[index].clone())
+        let quarantine = self.key_quarantine.get(provider)?;
+        let key_count = provider_config.api_keys.len();
+        let start = counter.fetch_add(1, Ordering::Relaxed) % key_count;
+        let now = Instant::now();
+        let index = (0..key_count)
+            .map(|offset| (start + offset) % key_count)
+            .find(|&i| !key_quarantined(&quarantine[i], now, provider, i))
+            .unwrap_or_else(|| {
+                warn!(
+                    "All API keys for provider '{}' are quarantined, using key {} anyway",
+                    provider,
+                    start + 1
+                );
+                start
+            });
+        Some((index, provider_config.api_keys[index].clone()))
+    }
+
+    /// Skip a provider key for `api_key_cooldown` after the upstream rejected it
+    pub fn quarantine_api_key(&self, provider: &str, index: usize) {
+        if let Some(slot) = self.key_quarantine.get(provider).and_then(|q| q.get(index)) {
+            *slot.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(Instant::now() + self.api_key_cooldown);
+            warn!(
+                "Quarantined API key {} for provider '{}' for {}s after an auth failure",
+                index + 1,
+                provider,
+                self.api_key_cooldown.as_secs()
+            );
+        }
+    }
+
+    /// Look up pricing for a model, trying the full "provider/model" name before the bare model name
+    pub fn pricing_for(&self, model: &str) -> Option<&ModelPricing> {
+        self.pricing.get(model).or_else(|| {
+            model
+                .split_once('/')
+                .and_then(|(_, name)| self.pricing.get(name))
+        })
+    }
+
+    /// Apply the provider's `[transforms.<provider>]` rules to a request body, returning whether
+    /// anything changed
+    pub fn transform_request(&self, provider: &str, body: &mut serde_json::Value) -> bool {
+        let changed = self
+            .transforms
+            .get(provider)
+            .is_some_and(|transform| transform.apply(body));
+        if changed {
+            info!("Applied request transforms for provider: {}", provider);
+        }
+        changed
+    }
+
+    /// Add the `[defaults]` fields the request body doesn't set, returning whether any were added.
+    /// Fields the client sent, even as null, are left alone.
+    pub fn apply_defaults(&self, body: &mut serde_json::Value) -> bool {
+        let Some(body) = body.as_object_mut() else {
+            return false;
+        };
+        let mut added = Vec::new();
+        for (field, value) in &self.defaults {
+            if !body.contains_key(field) {
+                body.insert(field.clone(), value.clone());
+                added.push(field.as_str());
+            }
+        }
+        if !added.is_empty() {
+            info!("Applied request defaults: {}", added.join(", "));
+        }
+        !added.is_empty()
+    }
+
+    /// Clamp the body's token limits to `max_tokens_limit`, returning whether it changed.
+    /// `max_completion_tokens` and `max_tokens` are each clamped when present, and replaced with
+    /// the limit when they aren't a number (providers read `null` as no limit); a body with
+    /// neither gets `max_tokens` set to the limit when `set_missing` is true.
+    pub fn enforce_max_tokens(&self, body: &mut serde_json::Value, set_missing: bool) -> bool {
+        let (Some(limit), Some(body)) = (self.max_tokens_limit, body.as_object_mut()) else {
+            return false;
+        };
+        let mut changed = false;
+        let mut present = false;
+        for field in ["max_completion_tokens", "max_tokens"] {
+            let Some(requested) = body.get(field) else {
+                continue;
+            };
+            present = true;
+            match requested.as_f64() {
+                Some(n) if n <= limit as f64 => continue,
+                Some(n) => info!("Clamped {} from {} to max_tokens_limit {}", field, n, limit),
+                None => info!(
+                    "Replaced non-numeric {} {} with max_tokens_limit {}",
+                    field, requested, limit
+                ),
+            }
+            body.insert(field.to_string(), limit.into());
+            changed = true;
+        }
+        if !present && set_missing {
+            info!("Set max_tokens to max_tokens_limit {}", limit);
+            body.insert("max_tokens".to_string(), limit.into());
+            changed = true;
+        }
+        changed
+    }
+
+    /// Fallback chain for a model, matched like `pricing_for`
+    pub fn fallback_models(&self, model: &str) -> &[String] {
+        self.fallback
+            .get(model)
+            .or_else(|| {
+                model
+                    .split_once('/')
+                    .and_then(|(_, name)| self.fallback.get(name))
+            })
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Remove `route_prefix` from an incoming path; paths outside the prefix are returned unchanged
+    pub fn strip_route_prefix<'a>(&self, request_path: &'a str) -> &'a str {
+        match request_path.strip_prefix(self.route_prefix.as_str()) {
+            Some("") if !self.route_prefix.is_empty() => "/",
+            Some(rest) if rest.starts_with('/') => rest,
+            _ => request_path,
+        }
+    }
+
+    /// Whether a path (after `route_prefix` is stripped) is under one of `allowed_paths`. The
+    /// bare root is always served, since `upstream_path` maps it to the chat compat path.
+    pub fn path_allowed(&self, path: &str) -> bool {
+        path.trim_end_matches('/').is_empty()
+            || self.allowed_paths.iter().any(|allowed| {
+                let allowed = allowed.trim_end_matches('/');
+                path.strip_prefix(allowed)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+    }
+
+    /// Map an incoming request path to the upstream path.
+    /// Entries in `compat_paths` win; other `/v1/...` paths go to the matching `/compat/...`
+    /// endpoint, anything else is forwarded verbatim, and a bare `/` uses the chat compat path.
+    pub fn upstream_path(&self, request_path: &str) -> String {
+        let trimmed = request_path.trim_end_matches('/');
+        if let Some(mapped) = self.compat_paths.get(trimmed) {
+            return mapped.clone();
+        }
+        if trimmed.is_empty() {
+            return self.openai_compat_path.clone();
+        }
+        match trimmed.strip_prefix("/v1/") {
+            Some(rest) => format!("/compat/{}", rest),
+            None => trimmed.to_string(),
+        }
+    }
+
+    /// Get the full target URL for a gateway and upstream path
+    pub fn target_url(&self, gateway: &GatewayConfig, upstream_path: &str) -> String {
+        join_url(&gateway.base_url(), upstream_path)
+    }
+}
+
+/// Join a base URL and a path with exactly one `/` between them, whatever slashes either side
+/// has; a path that is only a query string (`?a=b`) is appended directly
+fn join_url(base: &str, path: &str) -> String {
+    let base = base.trim_end_matches('/');
+    let path = path.trim_start_matches('/');
+    if path.is_empty() || path.starts_with('?') {
+        format!("{}{}", base, path)
+    } else {
+        format!("{}/{}", base, path)
+    }
+}
+
+/// Normalize an upstream path to a single leading `/` and no trailing `/`,
+/// e.g. `compat/chat/completions/` becomes `/compat/chat/completions`
+fn normalize_path(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const GATEWAY: &str = "[[gateways]]\naccount_id = \"a\"\ngateway_id = \"g\"\ntoken = \"t\"\n";
+
+    /// Load a config from top-level settings plus a single gateway
+    fn config(settings: &str) -> Config {
+        Config::from_toml(&format!("{}\n{}", settings, GATEWAY), "test.toml", None).unwrap()
+    }
+
+    #[test]
+    fn bracketed_ipv6_bind_address_binds_a_listener() {
+        let config = config("bind_address = \"[::1]\"\nhost_port = 0");
+        assert_eq!(config.listen_addr, "[::1]:0");
+
+        let addr = config.socket_addr().unwrap();
+        let listener = std::net::TcpListener::bind(addr).unwrap();
+        assert_eq!(listener.local_addr().unwrap().ip(), IpAddr::from([0, 0, 0, 0, 0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn default_allowed_paths_cover_the_built_in_mappings() {
+        let config = config("");
+        for path in ["/", "/v1/chat/completions", "/chat/completions", "/embeddings", "/compat/x"] {
+            assert!(config.path_allowed(path), "{} should be proxied", path);
+        }
+        for path in ["/favicon.ico", "/wp-login.php", "/chat/completionsx"] {
+            assert!(!config.path_allowed(path), "{} should get 404", path);
+        }
     }
 
-    /// Get the full target URL for the next gateway
-    pub fn next_target_url(&self) -> String {
-        let gateway = self.next_gateway();
-        format!("{}{}", gateway.base_url(), self.openai_compat_path)
+    #[test]
+    fn join_url_uses_exactly_one_slash() {
+        let cases = [
+            ("http://mock:8080", "/compat/chat/completions", "http://mock:8080/compat/chat/completions"),
+            ("http://mock:8080", "compat/chat/completions", "http://mock:8080/compat/chat/completions"),
+            ("http://mock:8080/", "/compat/chat/completions", "http://mock:8080/compat/chat/completions"),
+            ("http://mock:8080/", "compat/chat/completions", "http://mock:8080/compat/chat/completions"),
+            ("http://mock:8080/base", "/compat/x?a=b", "http://mock:8080/base/compat/x?a=b"),
+            ("http://mock:8080/base/", "?a=b", "http://mock:8080/base?a=b"),
+            ("http://mock:8080", "", "http://mock:8080"),
+            ("http://mock:8080/", "", "http://mock:8080"),
+            ("http://mock:8080/", "/", "http://mock:8080"),
+        ];
+        for (base, path, expected) in cases {
+            assert_eq!(join_url(base, path), expected, "join_url({:?}, {:?})", base, path);
+        }
+    }
+
+    #[test]
+    fn normalize_path_keeps_one_leading_slash() {
+        let cases = [
+            ("/compat/x", "/compat/x"),
+            ("compat/x", "/compat/x"),
+            ("/compat/x/", "/compat/x"),
+            ("compat/x/", "/compat/x"),
+            ("", "/"),
+            ("/", "/"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(normalize_path(path), expected, "normalize_path({:?})", path);
+        }
     }
 
-    /// Get the cf-aig-authorization token for the current gateway
-    pub fn current_gateway_token(&self) -> &str {
-        // Get the same gateway that was just selected
-        let index = (self.gateway_counter.load(Ordering::Relaxed).wrapping_sub(1)) % self.gateways.len();
-        &self.gateways[index].token
+    #[test]
+    fn enforce_max_tokens_caps_every_token_field() {
+        let config = config("max_tokens_limit = 100");
+        let cases = [
+            (
+                json!({"max_completion_tokens": 10, "max_tokens": 1_000_000}),
+                json!({"max_completion_tokens": 10, "max_tokens": 100}),
+                true,
+            ),
+            (json!({"max_tokens": null}), json!({"max_tokens": 100}), true),
+            (json!({"max_tokens": "99999"}), json!({"max_tokens": 100}), true),
+            (
+                json!({"max_completion_tokens": null}),
+                json!({"max_completion_tokens": 100}),
+                true,
+            ),
+            (json!({"max_tokens": 50}), json!({"max_tokens": 50}), false),
+            (json!({}), json!({"max_tokens": 100}), true),
+        ];
+        for (mut body, expected, changed) in cases {
+            let original = body.clone();
+            assert_eq!(config.enforce_max_tokens(&mut body, true), changed, "{}", original);
+            assert_eq!(body, expected, "{}", original);
+        }
+
+        // Other endpoints only get limits they asked for clamped
+        let mut body = json!({});
+        assert!(!config.enforce_max_tokens(&mut body, false));
+        assert_eq!(body, json!({}));
     }
 }
@@ -0,0 +1,83 @@
+use crate::config::ModelPricing;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Token usage reported by the upstream provider for one request
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+}
+
+impl Usage {
+    /// Read the `usage` object from a response body, accepting both OpenAI
+    /// (`prompt_tokens`/`completion_tokens`) and Anthropic (`input_tokens`/`output_tokens`) names
+    pub fn from_response(body: &[u8]) -> Option<Self> {
+        let json: Value = serde_json::from_slice(body).ok()?;
+        let usage = json.get("usage")?;
+        let count = |keys: [&str; 2]| {
+            keys.iter()
+                .find_map(|key| usage.get(*key).and_then(|v| v.as_u64()))
+        };
+        let input_tokens = count(["prompt_tokens", "input_tokens"]);
+        let output_tokens = count(["completion_tokens", "output_tokens"]);
+        if input_tokens.is_none() && output_tokens.is_none() {
+            return None;
+        }
+        Some(Self {
+            input_tokens: input_tokens.unwrap_or(0),
+            output_tokens: output_tokens.unwrap_or(0),
+        })
+    }
+
+    /// Cost of this usage at the given per-token prices
+    pub fn cost(&self, pricing: &ModelPricing) -> f64 {
+        self.input_tokens as f64 * pricing.input + self.output_tokens as f64 * pricing.output
+    }
+}
+
+/// Accumulated usage and cost for a single model
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ModelTotals {
+    pub requests: u64,
+    pub input_tokens: u64,
+    pub output_tokens: u64,
+    pub cost: f64,
+}
+
+/// Running cost totals shared across all requests
+#[derive(Debug, Default)]
+pub struct CostTracker {
+    models: Mutex<HashMap<String, ModelTotals>>,
+}
+
+/// Snapshot of the running totals, as served by /admin/costs
+#[derive(Debug, Serialize)]
+pub struct CostSummary {
+    pub total_cost: f64,
+    pub models: HashMap<String, ModelTotals>,
+}
+
+impl CostTracker {
+    /// Add a request's usage to the totals and return the new overall total cost
+    pub fn record(&self, model: &str, usage: Usage, cost: f64) -> f64 {
+        let mut models = self.models.lock().unwrap_or_else(|e| e.into_inner());
+        let totals = models.entry(model.to_string()).or_default();
+        totals.requests += 1;
+        totals.input_tokens += usage.input_tokens;
+        totals.output_tokens += usage.output_tokens;
+        totals.cost += cost;
+        models.values().map(|t| t.cost).sum()
+    }
+
+    /// Copy the current totals
+    pub fn summary(&self) -> CostSummary {
+        let models = self.models.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        CostSummary {
+            total_cost: models.values().map(|t| t.cost).sum(),
+            models,
+        }
+    }
+}
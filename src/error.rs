@@ -0,0 +1,35 @@
+use thiserror::Error;
+
+/// Error returned by the library's public functions, grouped by what failed
+#[derive(Debug, Error)]
+pub enum SnakeError {
+    /// The config file could not be read, parsed or validated
+    #[error("{0}")]
+    Config(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// A request to a gateway or provider failed
+    #[error("{0}")]
+    Upstream(String),
+    /// Certificates or keys could not be loaded
+    #[error("{0}")]
+    Tls(String),
+    /// Checking for, downloading or installing a release failed
+    #[error("{0}")]
+    Update(String),
+    /// Installing or controlling the system service failed
+    #[error("{0}")]
+    Service(String),
+}
+
+impl From<reqwest::Error> for SnakeError {
+    fn from(e: reqwest::Error) -> Self {
+        SnakeError::Upstream(e.to_string())
+    }
+}
+
+impl From<self_update::errors::Error> for SnakeError {
+    fn from(e: self_update::errors::Error) -> Self {
+        SnakeError::Update(e.to_string())
+    }
+}
@@ -0,0 +1,85 @@
+use crate::config::Config;
+use crate::proxy::AppState;
+use axum::{extract::State, http::StatusCode, response::IntoResponse};
+use reqwest::Client;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Timeout for each gateway connectivity probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the cached readiness result is refreshed
+const READINESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Liveness probe: the process is up and serving requests
+pub async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+/// HEAD every configured gateway URL concurrently, returning results in `gateways` order.
+/// Any HTTP response counts as reachable, since gateway roots answer HEAD with a 4xx.
+pub async fn probe_gateways(client: &Client, config: &Config) -> Vec<Result<StatusCode, String>> {
+    let probes: Vec<_> = config
+        .gateways
+        .iter()
+        .map(|gateway| {
+            let request = client.head(gateway.base_url()).timeout(PROBE_TIMEOUT);
+            tokio::spawn(async move { request.send().await.map(|r| r.status()) })
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(probes.len());
+    for probe in probes {
+        results.push(match probe.await {
+            Ok(result) => result.map_err(|e| error_chain(&e)),
+            Err(e) => Err(e.to_string()),
+        });
+    }
+    results
+}
+
+/// Render an error with its sources; reqwest's own message omits the underlying cause
+fn error_chain(e: &reqwest::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = std::error::Error::source(e);
+    while let Some(cause) = source {
+        message.push_str(": ");
+        message.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    message
+}
+
+/// Readiness probe: at least one gateway answered the last connectivity check
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    if state.ready.load(Ordering::Relaxed) {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "gateway unreachable")
+    }
+}
+
+/// Periodically re-run the connectivity check and update the readiness flag
+pub fn spawn_readiness_refresh(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(READINESS_INTERVAL);
+        // The startup check already ran, skip the immediate first tick
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            // Use the shared upstream client so the check sees the same TLS and proxy settings
+            let current = state.current();
+            let results = probe_gateways(&current.client, &current.config).await;
+            let reachable = results.iter().any(Result::is_ok);
+
+            let was_ready = state.ready.swap(reachable, Ordering::Relaxed);
+            if was_ready && !reachable {
+                warn!("Readiness check failed: no configured gateway is reachable");
+            } else if !was_ready && reachable {
+                info!("Readiness check recovered: a configured gateway is reachable");
+            }
+        }
+    });
+}
@@ -0,0 +1,56 @@
+//! Snake - the API proxy, as a library.
+//!
+//! Build an [`AppState`] from a loaded [`Config`] and mount [`build_router`] into an existing
+//! axum application. The `snake` binary is a thin CLI over the same modules.
+
+pub mod access_log;
+pub mod admin;
+mod anthropic;
+pub mod bench;
+pub mod config;
+mod cost;
+pub mod error;
+pub mod health;
+pub mod logs;
+pub mod proxy;
+pub mod service;
+mod stream;
+pub mod test;
+pub mod tls;
+pub mod update;
+pub mod watch;
+#[cfg(windows)]
+pub mod winservice;
+
+use axum::Router;
+
+pub use config::{Config, ConfigOverrides};
+pub use error::SnakeError;
+pub use proxy::{AppState, passthrough_client, proxy_handler, upstream_client_builder};
+
+/// The proxy's routes: `/healthz`, `/readyz`, the localhost-only `/admin/*` routes and the
+/// catch-all proxy route, mounted under `route_prefix` when one is configured.
+///
+/// Admin routes tell local clients apart by their peer address, so serve the router with
+/// `into_make_service_with_connect_info::<SocketAddr>()`; without it they refuse every caller.
+/// Over a Unix socket, add the `admin::UnixSocketClient` extension to mark callers as local.
+pub fn build_router(state: AppState) -> Router {
+    let proxy_route = format!("{}/{{*path}}", state.config.route_prefix);
+    // The wildcard needs at least one segment, so the bare root gets its own route
+    let root_route = format!("{}/", state.config.route_prefix);
+
+    // Admin routes are restricted to localhost
+    let admin_routes = Router::new()
+        .route("/admin/costs", axum::routing::get(admin::costs))
+        .route("/admin/stats", axum::routing::get(admin::stats))
+        .route_layer(axum::middleware::from_fn(admin::local_only));
+
+    // Health probes and admin routes are static, so they take priority over the catch-all proxy route
+    Router::new()
+        .route("/healthz", axum::routing::get(health::healthz))
+        .route("/readyz", axum::routing::get(health::readyz))
+        .merge(admin_routes)
+        .route(&root_route, axum::routing::any(proxy_handler))
+        .route(&proxy_route, axum::routing::any(proxy_handler))
+        .with_state(state)
+}
@@ -0,0 +1,258 @@
+use crate::error::SnakeError;
+use crate::service;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::process::{Command, Stdio};
+use tracing::Level;
+
+/// Settings for `snake logs`
+pub struct LogOptions {
+    /// Service name, as given to `service start --name`
+    pub name: String,
+    /// Read the systemd user unit's journal
+    pub user_scope: bool,
+    /// Keep printing new lines as they are logged
+    pub follow: bool,
+    /// Number of past lines to read before following
+    pub lines: u32,
+    /// Only show lines at this level or more severe
+    pub level: Option<Level>,
+    /// Only show lines from this module (e.g. `proxy` or `snake::proxy`) and its submodules
+    pub module: Option<String>,
+}
+
+/// One parsed log line
+struct LogLine {
+    timestamp: String,
+    level: Level,
+    /// Module path the line was logged from, e.g. `snake::proxy`
+    target: String,
+    /// Span fields such as `request{request_id=...}`
+    context: String,
+    message: String,
+}
+
+/// Read the service's log, keep the lines matching the filters and print them compactly.
+/// Lines that aren't log output are shown unless a filter is active.
+#[cfg_attr(windows, allow(unreachable_code))]
+pub fn show_logs(options: &LogOptions) -> Result<(), SnakeError> {
+    #[cfg(windows)]
+    return Err(SnakeError::Service(format!(
+        "Reading logs is not supported for the Windows service {}; set access_log in the config",
+        options.name
+    )));
+
+    let mut command = log_command(options)?;
+    let mut child = command.stdout(Stdio::piped()).spawn()?;
+    let Some(stdout) = child.stdout.take() else {
+        return Err(SnakeError::Service("Log viewer has no output".to_string()));
+    };
+
+    let mut out = std::io::stdout().lock();
+    let color = out.is_terminal();
+    let filtered = options.level.is_some() || options.module.is_some();
+    for line in BufReader::new(stdout).lines() {
+        let line = line?;
+        let written = match parse_line(&line) {
+            Some(parsed) if matches(&parsed, options) => {
+                writeln!(out, "{}", format_line(&parsed, color))
+            }
+            None if !filtered => writeln!(out, "{}", line),
+            _ => Ok(()),
+        };
+        // The reader went away, e.g. the output was piped into `head`
+        if written.is_err() {
+            let _ = child.kill();
+            break;
+        }
+    }
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(SnakeError::Service(format!(
+            "Log viewer exited with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// journalctl for the systemd unit, or tail on the launchd log file
+fn log_command(options: &LogOptions) -> Result<Command, SnakeError> {
+    if cfg!(target_os = "macos") {
+        let mut tail = Command::new("tail");
+        tail.arg("-n").arg(options.lines.to_string());
+        if options.follow {
+            tail.arg("-f");
+        }
+        tail.arg(service::launchd_log_path(&options.name)?);
+        return Ok(tail);
+    }
+
+    let mut journalctl = Command::new("journalctl");
+    if options.user_scope {
+        journalctl.arg("--user");
+    }
+    // `cat` output is just the message, i.e. the line snake wrote
+    journalctl
+        .arg("-u")
+        .arg(service::unit_name(&options.name)?)
+        .args(["-o", "cat", "--no-pager", "-n"])
+        .arg(options.lines.to_string());
+    if options.follow {
+        journalctl.arg("-f");
+    }
+    Ok(journalctl)
+}
+
+/// Parse a JSON log line or the default text format
+/// (`<timestamp> <LEVEL> <span>{..}: <target>: <message>`)
+fn parse_line(line: &str) -> Option<LogLine> {
+    let line = strip_ansi(line);
+    let line = line.trim();
+    if line.starts_with('{') {
+        return parse_json_line(line);
+    }
+
+    // Levels are padded to five characters, so INFO and WARN are preceded by two spaces
+    let (timestamp, rest) = line.split_once(char::is_whitespace)?;
+    let (level, rest) = rest.trim_start().split_once(char::is_whitespace)?;
+    let level = level.parse().ok()?;
+    let rest = rest.trim_start();
+
+    // Spans come first and contain `{`; the target is the first plain module path
+    let mut context = Vec::new();
+    let mut remaining = rest;
+    loop {
+        let (segment, tail) = remaining.split_once(": ")?;
+        if segment.contains('{') {
+            context.push(segment);
+            remaining = tail;
+            continue;
+        }
+        if segment.contains(char::is_whitespace) {
+            return None;
+        }
+        return Some(LogLine {
+            timestamp: timestamp.to_string(),
+            level,
+            target: segment.to_string(),
+            context: context.join(":"),
+            message: tail.to_string(),
+        });
+    }
+}
+
+/// tracing-subscriber's JSON format: level, target and `fields.message`, other fields appended
+fn parse_json_line(line: &str) -> Option<LogLine> {
+    let value: Value = serde_json::from_str(line).ok()?;
+    let level = value.get("level")?.as_str()?.parse().ok()?;
+    let text = |key: &str| {
+        value
+            .get(key)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string()
+    };
+    let fields = value.get("fields").and_then(Value::as_object);
+    let mut message = fields
+        .and_then(|fields| fields.get("message"))
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    for (key, field) in fields
+        .into_iter()
+        .flatten()
+        .filter(|(key, _)| *key != "message")
+    {
+        message.push_str(&format!(" {}={}", key, field));
+    }
+    let context = value
+        .get("span")
+        .and_then(Value::as_object)
+        .map(|span| {
+            span.iter()
+                .filter(|(key, _)| *key != "name")
+                .map(|(key, field)| format!("{}={}", key, field))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .unwrap_or_default();
+    Some(LogLine {
+        timestamp: text("timestamp"),
+        level,
+        target: text("target"),
+        context,
+        message,
+    })
+}
+
+fn matches(line: &LogLine, options: &LogOptions) -> bool {
+    // tracing orders levels by verbosity, so more severe levels compare lower
+    if let Some(level) = options.level
+        && line.level > level
+    {
+        return false;
+    }
+    match &options.module {
+        Some(module) => {
+            let module = module.strip_prefix("snake::").unwrap_or(module);
+            let target = line.target.strip_prefix("snake::").unwrap_or(&line.target);
+            target == module || target.starts_with(&format!("{}::", module))
+        }
+        None => true,
+    }
+}
+
+/// `HH:MM:SS LEVEL module message [context]`, colored by level on a terminal
+fn format_line(line: &LogLine, color: bool) -> String {
+    // RFC 3339 timestamps from tracing; keep the time of day
+    let time = line
+        .timestamp
+        .split_once('T')
+        .map_or(line.timestamp.as_str(), |(_, time)| time);
+    let time = time.get(..8).unwrap_or(time);
+    let module = line.target.strip_prefix("snake::").unwrap_or(&line.target);
+    let context = if line.context.is_empty() {
+        String::new()
+    } else {
+        format!("  [{}]", line.context)
+    };
+    let level = format!("{:<5}", line.level);
+    if !color {
+        return format!(
+            "{} {} {:<8} {}{}",
+            time, level, module, line.message, context
+        );
+    }
+    let level_color = match line.level {
+        Level::ERROR => "31",
+        Level::WARN => "33",
+        Level::INFO => "32",
+        Level::DEBUG => "34",
+        Level::TRACE => "35",
+    };
+    format!(
+        "\x1b[2m{}\x1b[0m \x1b[{}m{}\x1b[0m \x1b[36m{:<8}\x1b[0m {}\x1b[2m{}\x1b[0m",
+        time, level_color, level, module, line.message, context
+    )
+}
+
+/// Remove terminal color codes, which the default formatter writes into the journal
+fn strip_ansi(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip `ESC [ ... <letter>`
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
@@ -1,37 +1,65 @@
-mod config;
-mod proxy;
-mod service;
-mod stream;
-mod test;
-mod update;
-
 use axum::Router;
+use axum::http::{HeaderMap, StatusCode, Uri, header};
+use axum::response::{IntoResponse, Redirect};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use clap::{Parser, Subcommand};
-use config::Config;
-use proxy::{AppState, proxy_handler};
-use reqwest::Client;
+use snake::bench::{self, BenchOptions, OutputFormat};
+use snake::config::{STDIN_CONFIG, TlsSource};
+use snake::logs::{self, LogOptions};
+use snake::service::{self, InstallOptions};
+use snake::test::{RotationOptions, run_test, TestMode as TestModeEnum};
+use snake::update::{Channel, UpdateOptions, check_and_update};
+use snake::{
+    AppState, Config, ConfigOverrides, SnakeError, access_log, build_router, health, tls,
+    passthrough_client, upstream_client_builder, watch,
+};
 use std::env;
 use std::net::SocketAddr;
-use test::{run_test, TestMode as TestModeEnum};
-use tracing::{Level, error, info};
+use tracing::{Level, error, info, warn};
 use tracing_subscriber::FmtSubscriber;
-use update::check_and_update;
-use axum_server::tls_rustls::RustlsConfig;
 
 // --- CLI Structure ---
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const REPO_OWNER: &str = "v1cc0";
 const REPO_NAME: &str = "snake";
 
+// --- Exit codes, so scripts and systemd restart policies can tell failures apart ---
+/// Any failure without a more specific code (I/O, binding the listener, ...)
+const EXIT_FAILURE: i32 = 1;
+/// The config file is missing, invalid or fails `config check`
+const EXIT_CONFIG: i32 = 2;
+/// No configured gateway is reachable at startup
+const EXIT_CONNECTIVITY: i32 = 3;
+/// The TLS certificate or key, or the upstream client certificate, cannot be loaded
+const EXIT_TLS: i32 = 4;
+/// `snake test` ran and at least one request failed
+const EXIT_TEST_FAILED: i32 = 5;
+/// `snake update` could not check, download or install a release
+const EXIT_UPDATE: i32 = 6;
+/// A `snake service` command failed
+const EXIT_SERVICE: i32 = 7;
+
 #[derive(Parser)]
 #[command(name = "snake")]
 #[command(version = VERSION)]
 #[command(about = "Snake - the API proxy", long_about = None)]
 struct Cli {
-    /// Path to config file (default: config.toml)
-    #[arg(short, long, global = true, default_value = "config.toml")]
+    /// Path to config file, or - to read it from stdin (default: config.toml)
+    #[arg(short, long, global = true, env = "SNAKE_CONFIG", default_value = "config.toml")]
     config: String,
 
+    /// Listen on this port instead of host_port/https_port from the config file
+    #[arg(long, global = true)]
+    port: Option<u16>,
+
+    /// Merge [instances.<NAME>] from the config file over the base settings
+    #[arg(long, global = true, value_name = "NAME")]
+    instance: Option<String>,
+
+    /// Accept gateways repeated verbatim (same account_id, gateway_id and token) to weight traffic
+    #[arg(long, global = true)]
+    allow_duplicate_gateways: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -46,24 +74,94 @@ enum Commands {
         /// GitHub personal access token for downloading releases (optional)
         #[arg(short, long)]
         token: Option<String>,
+        /// Release channel: stable ignores prereleases, beta includes them
+        #[arg(long, value_enum, default_value_t = Channel::Stable)]
+        channel: Channel,
+        /// Show what would be updated without downloading or installing
+        #[arg(long)]
+        dry_run: bool,
+        /// Install this exact release tag (e.g. v0.1.28), even if it is a downgrade
+        #[arg(long = "version", value_name = "TAG", conflicts_with = "channel")]
+        target_version: Option<String>,
+        /// After installing, also verify the new binary accepts the --config file
+        #[arg(long)]
+        check_config: bool,
+        /// Install the new binary without restarting a running snake.service
+        #[arg(long)]
+        no_restart: bool,
+        /// Name of the installed service to restart (as given to `service start --name`)
+        #[arg(long, value_name = "NAME", default_value = service::DEFAULT_SERVICE_NAME)]
+        service_name: String,
     },
     /// Start the proxy server (default if no command specified)
-    Serve,
+    Serve {
+        /// Start without checking that the configured gateways are reachable (offline or mock setups)
+        #[arg(long)]
+        skip_connectivity_check: bool,
+        /// Reload the config file whenever it changes on disk
+        #[arg(long)]
+        watch: bool,
+    },
     /// Test the proxy configuration and connection
     Test {
         #[command(subcommand)]
         mode: Option<TestMode>,
     },
+    /// Measure throughput and latency under sustained concurrent load
+    Bench {
+        /// Provider whose test_model and api_keys are used for the requests
+        #[arg(long)]
+        provider: String,
+        /// Number of concurrent workers
+        #[arg(long, default_value_t = 10)]
+        concurrency: usize,
+        /// How long to keep sending requests (e.g. 30s, 2m, 500ms)
+        #[arg(long, default_value = "30s", value_parser = bench::parse_duration)]
+        duration: std::time::Duration,
+        /// Start an in-process proxy instead of targeting the running server
+        #[arg(long)]
+        embedded: bool,
+        /// Report format
+        #[arg(long, value_enum, default_value_t = OutputFormat::Human)]
+        format: OutputFormat,
+    },
     /// Configuration management
     Config {
         #[command(subcommand)]
         action: ConfigAction,
     },
-    /// Manage systemd service
+    /// Manage the system service (systemd on Linux, launchd on macOS)
     Service {
+        /// Use a systemd user unit (~/.config/systemd/user) instead of a system unit
+        #[arg(long, global = true)]
+        user_scope: bool,
+        /// Service name, so several instances can run as separate units (<NAME>.service)
+        #[arg(long, global = true, default_value = service::DEFAULT_SERVICE_NAME)]
+        name: String,
         #[command(subcommand)]
         action: ServiceAction,
     },
+    /// Show the service's log, parsed and filtered (journalctl on Linux, the log file on macOS)
+    Logs {
+        /// Keep printing new lines as they are logged
+        #[arg(short, long)]
+        follow: bool,
+        /// Only show this level and more severe ones (error, warn, info, debug, trace)
+        #[arg(long)]
+        level: Option<Level>,
+        /// Only show lines logged by this module, e.g. proxy or snake::config
+        #[arg(long)]
+        module: Option<String>,
+        /// Number of past lines to read
+        #[arg(short = 'n', long, default_value_t = 100)]
+        lines: u32,
+        /// Service name, as given to `service start --name`
+        #[arg(long, default_value = service::DEFAULT_SERVICE_NAME)]
+        name: String,
+        /// Read the systemd user unit's journal
+        #[arg(long)]
+        user_scope: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -71,7 +169,14 @@ enum TestMode {
     /// Test all (gateways and providers)
     All,
     /// Test gateway rotation only
-    Gateway,
+    Gateway {
+        /// Number of full rotations through the gateways
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+        rotations: u32,
+        /// Message content sent with each request
+        #[arg(long, default_value = snake::test::DEFAULT_ROTATION_PROMPT)]
+        prompt: String,
+    },
     /// Test specific provider
     Provider {
         /// Provider name (e.g., openai, google-ai-studio, groq)
@@ -90,10 +195,35 @@ enum ConfigAction {
 
 #[derive(Subcommand)]
 enum ServiceAction {
-    /// Install and start the systemd service
-    Start,
-    /// Stop and uninstall the systemd service
+    /// Install and start the service
+    Start {
+        /// Account the system unit runs as (default: root for ports < 1024, otherwise the invoking user)
+        #[arg(long)]
+        user: Option<String>,
+        /// Unit description (default: "Snake - the API proxy")
+        #[arg(long)]
+        description: Option<String>,
+        /// Environment file loaded by the unit (default: .env in the working directory, if any)
+        #[arg(long, value_name = "PATH")]
+        env_file: Option<String>,
+        /// Seconds systemd waits before restarting the service after a crash (default: 5)
+        #[arg(long, value_name = "SECONDS")]
+        restart_sec: Option<u32>,
+        /// Memory cap for the unit (systemd MemoryMax=, e.g. 512M, 2G or 80%)
+        #[arg(long, value_name = "SIZE", value_parser = service::parse_memory_max)]
+        memory_max: Option<String>,
+        /// Cap on processes and threads for the unit (systemd TasksMax=, e.g. 512 or 50%)
+        #[arg(long, value_name = "N", value_parser = service::parse_tasks_max)]
+        tasks_max: Option<String>,
+    },
+    /// Stop and uninstall the service
     Stop,
+    /// Show service status
+    Status,
+    /// Restart the service
+    Restart,
+    /// Follow service logs
+    Logs,
 }
 
 #[tokio::main]
@@ -104,39 +234,98 @@ async fn main() {
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
-    // Initialize tracing (for logging)
+    // Parse CLI arguments
+    let cli = Cli::parse();
+
+    // Initialize tracing (for logging). Benchmarks only log warnings so per-request lines from
+    // the embedded proxy don't drown the report
+    let max_level = match cli.command {
+        Some(Commands::Bench { .. } | Commands::Logs { .. }) => Level::WARN,
+        _ => Level::INFO,
+    };
     let subscriber = FmtSubscriber::builder()
-        .with_max_level(Level::INFO)
+        .with_max_level(max_level)
         .finish();
 
     tracing::subscriber::set_global_default(subscriber)
         .expect("Failed to set tracing subscriber");
 
     info!("Starting Snake - the API proxy v{}", VERSION);
-
-    // Parse CLI arguments
-    let cli = Cli::parse();
+    let overrides = ConfigOverrides {
+        port: cli.port,
+        instance: cli.instance.clone(),
+        allow_duplicate_gateways: cli.allow_duplicate_gateways,
+    };
 
     // Handle commands
+    let mut skip_check_flag = false;
+    let mut watch_config = false;
     match cli.command {
-        Some(Commands::Update { yes, token }) => {
-            if let Err(e) = check_and_update(VERSION, REPO_OWNER, REPO_NAME, yes, token).await {
+        Some(Commands::Update {
+            yes,
+            token,
+            channel,
+            dry_run,
+            target_version,
+            check_config,
+            no_restart,
+            service_name,
+        }) => {
+            let options = UpdateOptions {
+                skip_confirm: yes,
+                token,
+                channel,
+                dry_run,
+                target_version,
+                check_config: check_config.then(|| cli.config.clone()),
+                no_restart,
+                service_name: Some(service_name),
+            };
+            if let Err(e) = check_and_update(VERSION, REPO_OWNER, REPO_NAME, options).await {
                 error!("Update failed: {}", e);
                 eprintln!("Error: {}", e);
-                std::process::exit(1);
+                std::process::exit(exit_code(&e));
             }
             return;
         }
         Some(Commands::Test { mode }) => {
             let test_mode = match mode.unwrap_or(TestMode::All) {
                 TestMode::All => TestModeEnum::All,
-                TestMode::Gateway => TestModeEnum::Gateway,
+                TestMode::Gateway { rotations, prompt } => {
+                    TestModeEnum::Gateway(RotationOptions { rotations, prompt })
+                }
                 TestMode::Provider { name } => TestModeEnum::Provider(name),
             };
-            if let Err(e) = run_test(&cli.config, test_mode).await {
+            if let Err(e) = run_test(&cli.config, &overrides, test_mode).await {
                 error!("Test failed: {}", e);
                 eprintln!("\n❌ Test failed: {}", e);
-                std::process::exit(1);
+                // Failed requests are what the test is looking for, not an upstream outage
+                let code = match e {
+                    SnakeError::Upstream(_) => EXIT_TEST_FAILED,
+                    _ => exit_code(&e),
+                };
+                std::process::exit(code);
+            }
+            return;
+        }
+        Some(Commands::Bench {
+            provider,
+            concurrency,
+            duration,
+            embedded,
+            format,
+        }) => {
+            let options = BenchOptions {
+                provider,
+                concurrency,
+                duration,
+                embedded,
+                format,
+            };
+            if let Err(e) = bench::run_bench(&cli.config, &overrides, options).await {
+                error!("Benchmark failed: {}", e);
+                eprintln!("\n❌ Benchmark failed: {}", e);
+                std::process::exit(exit_code(&e));
             }
             return;
         }
@@ -144,41 +333,110 @@ async fn main() {
             match action {
                 ConfigAction::Check { path } => {
                     let config_path = path.as_ref().unwrap_or(&cli.config);
-                    if let Err(e) = check_config(config_path) {
+                    if let Err(e) = check_config(config_path, &overrides).await {
                         error!("Config check failed: {}", e);
                         eprintln!("\n❌ Config check failed: {}", e);
-                        std::process::exit(1);
+                        std::process::exit(exit_code(&e));
                     }
                 }
             }
             return;
         }
-        Some(Commands::Service { action }) => {
+        Some(Commands::Logs {
+            follow,
+            level,
+            module,
+            lines,
+            name,
+            user_scope,
+        }) => {
+            let options = LogOptions {
+                name,
+                user_scope,
+                follow,
+                lines,
+                level,
+                module,
+            };
+            if let Err(e) = logs::show_logs(&options) {
+                eprintln!("\n❌ {}", e);
+                std::process::exit(exit_code(&e));
+            }
+            return;
+        }
+        Some(Commands::Service {
+            user_scope,
+            name,
+            action,
+        }) => {
             let result = match action {
-                ServiceAction::Start => service::install_service(),
-                ServiceAction::Stop => service::uninstall_service(),
+                ServiceAction::Start {
+                    user,
+                    description,
+                    env_file,
+                    restart_sec,
+                    memory_max,
+                    tasks_max,
+                } => {
+                    service::install_service(
+                        &cli.config,
+                        &name,
+                        InstallOptions {
+                            user,
+                            user_scope,
+                            instance: cli.instance.clone(),
+                            allow_duplicate_gateways: cli.allow_duplicate_gateways,
+                            description,
+                            env_file,
+                            restart_sec,
+                            memory_max,
+                            tasks_max,
+                        },
+                    )
+                }
+                ServiceAction::Stop => service::uninstall_service(user_scope, &name),
+                ServiceAction::Status => service::service_status(user_scope, &name),
+                ServiceAction::Restart => service::restart_service(user_scope, &name),
+                ServiceAction::Logs => service::service_logs(user_scope, &name),
             };
             if let Err(e) = result {
                 error!("Service command failed: {}", e);
                 eprintln!("\n❌ Service command failed: {}", e);
-                std::process::exit(1);
+                std::process::exit(exit_code(&e));
             }
             return;
         }
-        Some(Commands::Serve) | None => {
+        Some(Commands::Serve {
+            skip_connectivity_check,
+            watch,
+        }) => {
+            skip_check_flag = skip_connectivity_check;
+            watch_config = watch;
+        }
+        None => {
             // Continue to serve mode (default)
         }
     }
 
+    // Started by the Service Control Manager: report status and take stop requests from it
+    #[cfg(windows)]
+    if snake::winservice::connect_to_scm() {
+        info!("Connected to the Windows Service Control Manager");
+    }
+
     // Load configuration from specified path
-    let config = match Config::from_toml(&cli.config) {
+    let config = match Config::load(&cli.config, &overrides) {
         Ok(cfg) => cfg,
         Err(e) => {
             error!("Configuration error: {}", e);
             eprintln!("Configuration error: {}", e);
-            return;
+            std::process::exit(exit_code(&e));
         }
     };
+    let skip_connectivity_check = skip_check_flag || config.skip_connectivity_check;
+    if config.dev.echo {
+        warn!("[dev] echo is enabled: JSON requests are answered locally and never reach upstream");
+    }
 
     info!(
         "Loaded {} gateway(s) for round-robin rotation",
@@ -188,150 +446,453 @@ async fn main() {
     // Display server mode and endpoints
     if config.https_server {
         info!("Server mode: HTTPS (port {})", config.https_port);
-        info!("  TLS Certificate: {}", config.tls_cert_path);
-        info!("  TLS Private Key: {}", config.tls_key_path);
+        match &config.tls {
+            TlsSource::Pem { cert_path, key_path } => {
+                info!("  TLS Certificate: {}", cert_path);
+                info!("  TLS Private Key: {}", key_path);
+            }
+            TlsSource::Pkcs12 { path, .. } => info!("  TLS PKCS#12 Bundle: {}", path),
+            TlsSource::Acme(acme) => info!("  TLS via ACME for: {}", acme.domains.join(", ")),
+        }
         info!(
-            "Public endpoint: https://0.0.0.0:{}/v1/chat/completions",
-            config.https_port
+            "Public endpoint: https://{}{}/v1/chat/completions",
+            config.listen_addr, config.route_prefix
         );
+    } else if let Some(socket_path) = &config.unix_socket {
+        info!("Server mode: HTTP (unix socket {})", socket_path);
     } else {
         info!("Server mode: HTTP (port {})", config.http_port);
         info!(
-            "Local endpoint: http://0.0.0.0:{}/v1/chat/completions",
-            config.http_port
+            "Local endpoint: http://{}{}/v1/chat/completions",
+            config.listen_addr, config.route_prefix
         );
     }
 
-    // Test network connectivity to Cloudflare AI Gateway before starting server
-    info!("Testing network connectivity to gateway.ai.cloudflare.com...");
-    let test_client = Client::builder()
-        .timeout(std::time::Duration::from_secs(10))
-        .build()
-        .expect("Failed to build HTTP client");
-
-    let test_url = "https://gateway.ai.cloudflare.com";
-    match test_client.head(test_url).send().await {
-        Ok(response) => {
-            if response.status().is_success() || response.status().is_redirection() {
-                info!("✓ Network connectivity test passed (status: {})", response.status());
-            } else {
-                error!("Network connectivity test failed: HTTP {}", response.status());
-                eprintln!("\n❌ Error: Cannot reach Cloudflare AI Gateway");
-                eprintln!("   URL: {}", test_url);
-                eprintln!("   Status: {}", response.status());
+    // Create a single, shared reqwest client for connection pooling and performance.
+    // It is built before the connectivity test so mTLS problems fail fast and the test uses it too.
+    let (client, passthrough) = match upstream_client_builder(&config)
+        .and_then(|builder| builder.build().map_err(SnakeError::from))
+        .and_then(|client| Ok((client, passthrough_client(&config)?)))
+    {
+        Ok(clients) => clients,
+        Err(e) => {
+            error!("Failed to configure upstream client: {}", e);
+            eprintln!("\n❌ Error: Failed to configure upstream HTTP client");
+            eprintln!("   {}", e);
+            if let (Some(cert_path), Some(key_path)) =
+                (&config.upstream_client_cert, &config.upstream_client_key)
+            {
                 eprintln!("\nPlease check:");
-                eprintln!("  1. Your internet connection");
-                eprintln!("  2. Firewall settings");
-                eprintln!("  3. DNS resolution for gateway.ai.cloudflare.com");
-                std::process::exit(1);
+                eprintln!("  1. Certificate file exists: {}", cert_path);
+                eprintln!("  2. Private key file exists: {}", key_path);
+                eprintln!("  3. Files are readable and in correct PEM format");
             }
+            std::process::exit(exit_code(&e));
         }
-        Err(e) => {
-            error!("Network connectivity test failed: {}", e);
-            eprintln!("\n❌ Error: Cannot reach Cloudflare AI Gateway");
-            eprintln!("   URL: {}", test_url);
-            eprintln!("   Error: {}", e);
-            eprintln!("\nPlease check:");
-            eprintln!("  1. Your internet connection");
-            eprintln!("  2. Firewall settings");
-            eprintln!("  3. DNS resolution for gateway.ai.cloudflare.com");
-            eprintln!("  4. Proxy settings (if applicable)");
-            std::process::exit(1);
+    };
+
+    // Test network connectivity to the configured gateways before starting server
+    if skip_connectivity_check {
+        warn!("Skipping the Cloudflare connectivity check; /readyz will always report ready");
+    } else {
+        verify_connectivity(&client, &config).await;
+    }
+    if config.warmup_connections {
+        // Each client has its own connection pool
+        for pool in [&client, &passthrough] {
+            warm_up_connections(pool, &config).await;
         }
     }
 
-    // Create a single, shared reqwest client for connection pooling and performance.
-    let client = Client::new();
-    let app_state = AppState {
-        client,
-        config: config.clone(),
-    };
+    let mut app_state = AppState::new(client, passthrough, config.clone());
+    if let Some(path) = &config.access_log {
+        match access_log::AccessLog::open(path).await {
+            Ok(log) => {
+                info!("Access log: {}", path);
+                app_state.access_log = Some(log);
+            }
+            Err(e) => {
+                error!("{}", e);
+                eprintln!("\n❌ Error: {}", e);
+                std::process::exit(exit_code(&e));
+            }
+        }
+    }
+    if !skip_connectivity_check {
+        health::spawn_readiness_refresh(app_state.clone());
+    }
+    if config.rotation_state_path.is_some() {
+        spawn_rotation_state_saver(config.clone());
+    }
+    if watch_config
+        && let Err(e) =
+            watch::spawn_config_watcher(app_state.clone(), cli.config.clone(), overrides.clone())
+    {
+        error!("{}", e);
+        eprintln!("\n❌ Error: {}", e);
+        std::process::exit(exit_code(&e));
+    }
+
+    let app = build_router(app_state);
 
-    // Define the application routes.
-    let app = Router::new()
-        .route("/{*path}", axum::routing::any(proxy_handler))
-        .with_state(app_state);
+    // A Unix domain socket replaces the TCP listener entirely
+    if let Some(socket_path) = &config.unix_socket {
+        serve_unix_socket(app, socket_path).await;
+        config.save_rotation_state();
+        return;
+    }
 
     // Parse the listening address
-    let addr: SocketAddr = match config.listen_addr.parse() {
+    let addr = match config.socket_addr() {
         Ok(addr) => addr,
-        Err(_) => {
-            error!("Failed to parse listen address: {}", config.listen_addr);
-            return;
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(exit_code(&e));
         }
     };
 
     // Start server based on HTTPS configuration
     if config.https_server {
         // HTTPS mode
-        info!("Starting HTTPS server on 0.0.0.0:{}", config.https_port);
+        info!("Starting HTTPS server on {}", addr);
 
-        // Load TLS configuration
-        let tls_config = match load_tls_config(&config.tls_cert_path, &config.tls_key_path).await {
-            Ok(cfg) => cfg,
-            Err(e) => {
-                error!("Failed to load TLS configuration: {}", e);
-                eprintln!("\n❌ Error: Failed to load TLS configuration");
-                eprintln!("   {}", e);
-                eprintln!("\nPlease check:");
-                eprintln!("  1. Certificate file exists: {}", config.tls_cert_path);
-                eprintln!("  2. Private key file exists: {}", config.tls_key_path);
-                eprintln!("  3. Files are readable and in correct PEM format");
-                std::process::exit(1);
-            }
-        };
+        // One handle drives graceful shutdown for the HTTPS server and the redirect listener
+        let handle = axum_server::Handle::new();
+        let shutdown_handle = handle.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            shutdown_handle.graceful_shutdown(Some(std::time::Duration::from_secs(30)));
+        });
 
-        info!("✓ TLS configuration loaded successfully");
-        info!("🚀 HTTPS proxy server ready on port {}", config.https_port);
+        if let Some(redirect_port) = config.redirect_http_port {
+            let redirect_addr = SocketAddr::new(addr.ip(), redirect_port);
+            let redirect_app = https_redirect_router(config.https_port);
+            let redirect_handle = handle.clone();
+            info!("Redirecting HTTP on {} to HTTPS port {}", redirect_addr, config.https_port);
+            tokio::spawn(async move {
+                if let Err(e) = axum_server::bind(redirect_addr)
+                    .handle(redirect_handle)
+                    .serve(redirect_app.into_make_service())
+                    .await
+                {
+                    error!("HTTP redirect server error on {}: {}", redirect_addr, e);
+                }
+            });
+        }
 
-        if let Err(e) = axum_server::bind_rustls(addr, tls_config)
-            .serve(app.into_make_service())
-            .await
-        {
+        let server = axum_server::bind(addr).handle(handle);
+        let service = app.into_make_service_with_connect_info::<SocketAddr>();
+        let result = match &config.tls {
+            TlsSource::Acme(acme) => {
+                let acceptor = tls::spawn_acme(acme);
+                info!("🚀 HTTPS proxy server ready on port {} (HTTP/2 + HTTP/1.1 via ALPN)", config.https_port);
+                server.acceptor(acceptor).serve(service).await
+            }
+            source => {
+                let tls_config = load_tls_or_exit(source).await;
+                tls::spawn_expiry_monitor(source.clone(), config.cert_expiry_warn_days);
+                tls::spawn_reload_on_sighup(tls_config.clone(), source.clone());
+                info!("🚀 HTTPS proxy server ready on port {} (HTTP/2 + HTTP/1.1 via ALPN)", config.https_port);
+                server.acceptor(RustlsAcceptor::new(tls_config)).serve(service).await
+            }
+        };
+        if let Err(e) = result {
             error!("HTTPS server error: {}", e);
         }
     } else {
         // HTTP mode
-        info!("Starting HTTP server on 0.0.0.0:{}", config.http_port);
+        info!("Starting HTTP server on {}", addr);
 
         let listener = match tokio::net::TcpListener::bind(addr).await {
             Ok(listener) => listener,
             Err(e) => {
                 error!("Failed to bind to address {}: {}", addr, e);
-                return;
+                std::process::exit(EXIT_FAILURE);
             }
         };
 
-        info!("🚀 HTTP proxy server ready on port {}", config.http_port);
+        info!("🚀 HTTP proxy server ready on port {} (HTTP/1.1 + h2c)", config.http_port);
 
-        if let Err(e) = axum::serve(listener, app).await {
+        if let Err(e) = axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        {
             error!("Server error: {}", e);
         }
     }
+
+    config.save_rotation_state();
+    #[cfg(windows)]
+    snake::winservice::report_stopped();
+}
+
+/// Load the certificate and key from disk, exiting with a checklist when they can't be used
+async fn load_tls_or_exit(source: &TlsSource) -> RustlsConfig {
+    match tls::load_tls_config(source).await {
+        Ok(tls_config) => {
+            info!("✓ TLS configuration loaded successfully");
+            tls_config
+        }
+        Err(e) => {
+            error!("Failed to load TLS configuration: {}", e);
+            eprintln!("\n❌ Error: Failed to load TLS configuration");
+            eprintln!("   {}", e);
+            eprintln!("\nPlease check:");
+            match source {
+                TlsSource::Pem { cert_path, key_path } => {
+                    eprintln!("  1. Certificate file exists: {}", cert_path);
+                    eprintln!("  2. Private key file exists: {}", key_path);
+                    eprintln!("  3. Files are readable and in correct PEM format");
+                }
+                TlsSource::Pkcs12 { path, .. } => {
+                    eprintln!("  1. PKCS#12 bundle exists and is readable: {}", path);
+                    eprintln!("  2. tls_pkcs12_password matches the bundle");
+                }
+                TlsSource::Acme(_) => {}
+            }
+            std::process::exit(exit_code(&e));
+        }
+    }
+}
+
+/// Process exit code for an error, by what failed
+fn exit_code(e: &SnakeError) -> i32 {
+    match e {
+        SnakeError::Config(_) => EXIT_CONFIG,
+        SnakeError::Upstream(_) => EXIT_CONNECTIVITY,
+        SnakeError::Tls(_) => EXIT_TLS,
+        SnakeError::Update(_) => EXIT_UPDATE,
+        SnakeError::Service(_) => EXIT_SERVICE,
+        SnakeError::Io(_) => EXIT_FAILURE,
+    }
+}
+
+/// Save the round-robin position every few seconds, in case the process dies without a clean shutdown
+fn spawn_rotation_state_saver(config: Config) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(10));
+        let mut saved = config.rotation_position();
+        loop {
+            interval.tick().await;
+            let position = config.rotation_position();
+            if position != saved {
+                config.save_rotation_state();
+                saved = position;
+            }
+        }
+    });
+}
+
+/// Send a HEAD to every gateway so the first requests find an open connection in the pool.
+/// Failures are only logged; the connectivity check decides whether startup goes ahead.
+async fn warm_up_connections(client: &reqwest::Client, config: &Config) {
+    let started = std::time::Instant::now();
+    let results = health::probe_gateways(client, config).await;
+    for (gateway, result) in config.gateways.iter().zip(&results) {
+        if let Err(e) = result {
+            warn!("Warmup of gateway {} failed: {}", gateway.gateway_id, e);
+        }
+    }
+    info!(
+        "✓ Warmed up connections to {} of {} gateway(s) in {}ms",
+        results.iter().filter(|result| result.is_ok()).count(),
+        results.len(),
+        started.elapsed().as_millis()
+    );
+}
+
+/// Probe every configured gateway. Exits with troubleshooting hints if none is reachable,
+/// and only warns when some of them are.
+async fn verify_connectivity(client: &reqwest::Client, config: &Config) {
+    info!("Testing network connectivity to {} gateway(s)...", config.gateways.len());
+    let results = health::probe_gateways(client, config).await;
+
+    let mut failures = Vec::new();
+    for (idx, (gateway, result)) in config.gateways.iter().zip(&results).enumerate() {
+        match result {
+            Ok(status) => info!(
+                "✓ Gateway {} ({}) reachable: {} (status: {})",
+                idx + 1,
+                gateway.gateway_id,
+                gateway.base_url(),
+                status
+            ),
+            Err(e) => {
+                warn!(
+                    "Gateway {} ({}) unreachable: {}: {}",
+                    idx + 1,
+                    gateway.gateway_id,
+                    gateway.base_url(),
+                    e
+                );
+                failures.push((idx, gateway, e));
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        info!("✓ Network connectivity test passed");
+        return;
+    }
+    if failures.len() < results.len() {
+        warn!(
+            "{} of {} gateway(s) unreachable; requests rotated to them will fail until they recover",
+            failures.len(),
+            results.len()
+        );
+        return;
+    }
+
+    error!("Network connectivity test failed: no gateway is reachable");
+    eprintln!("\n❌ Error: Cannot reach any configured gateway");
+    for (idx, gateway, e) in &failures {
+        eprintln!("   Gateway {}: {}", idx + 1, gateway.base_url());
+        eprintln!("   Error: {}", e);
+    }
+    eprintln!("\nPlease check:");
+    eprintln!("  1. Your internet connection");
+    eprintln!("  2. Firewall settings");
+    eprintln!("  3. DNS resolution for the gateway hosts");
+    eprintln!("  4. Proxy settings (if applicable)");
+    std::process::exit(EXIT_CONNECTIVITY);
+}
+
+/// Serve the proxy on a Unix domain socket, removing the socket file on shutdown
+#[cfg(unix)]
+async fn serve_unix_socket(app: Router, socket_path: &str) {
+    // Remove a stale socket left behind by an unclean exit
+    let path = std::path::Path::new(socket_path);
+    if path.exists() {
+        if let Err(e) = std::fs::remove_file(path) {
+            error!("Failed to remove stale socket {}: {}", socket_path, e);
+            std::process::exit(EXIT_FAILURE);
+        }
+        info!("Removed stale socket file: {}", socket_path);
+    }
+
+    let listener = match tokio::net::UnixListener::bind(path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind unix socket {}: {}", socket_path, e);
+            std::process::exit(EXIT_FAILURE);
+        }
+    };
+
+    info!("🚀 HTTP proxy server ready on unix socket {}", socket_path);
+
+    // Unix socket peers have no address; the marker lets admin routes recognize them
+    let app = app.layer(axum::Extension(snake::admin::UnixSocketClient));
+    if let Err(e) = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+    {
+        error!("Server error: {}", e);
+    }
+
+    match std::fs::remove_file(path) {
+        Ok(()) => info!("Removed socket file: {}", socket_path),
+        Err(e) => error!("Failed to remove socket file {}: {}", socket_path, e),
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_unix_socket(_app: Router, socket_path: &str) {
+    error!(
+        "unix_socket ({}) is only supported on Unix platforms",
+        socket_path
+    );
+}
+
+/// Router that permanently redirects every request to the same host and path over HTTPS
+fn https_redirect_router(https_port: u16) -> Router {
+    Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        let Some(host) = headers.get(header::HOST).and_then(|h| h.to_str().ok()) else {
+            return (StatusCode::BAD_REQUEST, "missing Host header").into_response();
+        };
+        // Drop the port from the Host header, keeping IPv6 literals like [::1] intact
+        let hostname = match host.rfind(':') {
+            Some(idx) if !host[idx..].contains(']') => &host[..idx],
+            _ => host,
+        };
+        let authority = if https_port == 443 {
+            hostname.to_string()
+        } else {
+            format!("{}:{}", hostname, https_port)
+        };
+        let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        Redirect::permanent(&format!("https://{}{}", authority, path)).into_response()
+    })
+}
+
+/// Resolve when the process receives Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => error!("Failed to listen for SIGTERM: {}", e),
+        }
+    };
+
+    #[cfg(windows)]
+    let terminate = snake::winservice::stop_requested();
+
+    #[cfg(not(any(unix, windows)))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutdown signal received, stopping server...");
 }
 
 /// Check if config file is valid and meets minimum requirements
-fn check_config(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+async fn check_config(config_path: &str, overrides: &ConfigOverrides) -> Result<(), SnakeError> {
     info!("Checking configuration file: {}", config_path);
 
     // Check if file exists
     let path = std::path::Path::new(config_path);
-    if !path.exists() {
-        return Err(format!("Config file not found: {}", config_path).into());
+    if config_path != STDIN_CONFIG {
+        if !path.exists() {
+            return Err(SnakeError::Config(format!("Config file not found: {}", config_path)));
+        }
+        println!("✓ Config file exists: {}", config_path);
     }
 
-    println!("✓ Config file exists: {}", config_path);
-
     // Try to load config
-    let config = Config::from_toml(config_path)?;
+    let config = Config::load(config_path, overrides)?;
 
     // Validate minimum requirements
     println!("\n📋 Configuration Summary:");
-    println!("  ├─ Host Port: {}", config.listen_addr.split(':').last().unwrap_or("unknown"));
+    if let Some(socket_path) = &config.unix_socket {
+        println!("  ├─ Unix Socket: {}", socket_path);
+    } else {
+        let addr = config.socket_addr()?;
+        println!("  ├─ Bind Address: {}", config.bind_address);
+        println!("  ├─ Host Port: {}", addr.port());
+        if let Some(redirect_port) = config.redirect_http_port {
+            println!("  ├─ HTTP Redirect Port: {} → HTTPS", redirect_port);
+        }
+    }
     println!("  ├─ Gateways: {}", config.gateways.len());
 
     if config.gateways.is_empty() {
-        return Err("At least one gateway must be configured".into());
+        return Err(SnakeError::Config(
+            "At least one gateway must be configured".to_string(),
+        ));
     }
 
     for (idx, gateway) in config.gateways.iter().enumerate() {
@@ -348,7 +909,17 @@ fn check_config(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
     for (name, provider) in &config.providers {
         if !provider.api_keys.is_empty() {
             provider_count += 1;
-            println!("      ├─ {}: {} key(s)", name, provider.api_keys.len());
+            let managed = if provider.server_managed_keys {
+                " (server-managed)"
+            } else {
+                ""
+            };
+            println!(
+                "      ├─ {}: {} key(s){}",
+                name,
+                provider.api_keys.len(),
+                managed
+            );
         }
     }
 
@@ -357,6 +928,99 @@ fn check_config(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
         println!("   The proxy will work but will use client-provided API keys only");
     }
 
+    let mut issues = config.issues();
+    // A certificate problem is also returned as a TLS error, for its own exit code
+    let mut tls_error = None;
+
+    // ACME certificates only exist once the server has obtained them, so report the settings
+    if let TlsSource::Acme(acme) = &config.tls {
+        println!("\n🔒 TLS (ACME):");
+        println!("  ├─ Domains: {}", acme.domains.join(", "));
+        if !acme.contact.is_empty() {
+            println!("  ├─ Contact: {}", acme.contact.join(", "));
+        }
+        println!(
+            "  ├─ Directory: Let's Encrypt {}",
+            if acme.staging { "staging" } else { "production" }
+        );
+        println!("  └─ Cache: {}", acme.cache_dir);
+        if config.https_port != 443 {
+            issues.warnings.push(format!(
+                "[acme] uses TLS-ALPN-01, which Let's Encrypt validates on port 443, \
+                 but https_port is {}; forward 443 to it",
+                config.https_port
+            ));
+        }
+    } else if config.https_server {
+        // Load the certificate and key exactly as `serve` would, then report the expiry
+        println!("\n🔒 TLS:");
+        let cert_path = config.tls.cert_file();
+        match tls::load_tls_config(&config.tls).await {
+            Ok(_) => {
+                match &config.tls {
+                    TlsSource::Pem { cert_path, key_path } => {
+                        println!("  ├─ Certificate: {}", cert_path);
+                        println!("  ├─ Private Key: {}", key_path);
+                    }
+                    TlsSource::Pkcs12 { path, .. } => println!("  ├─ PKCS#12 Bundle: {}", path),
+                    TlsSource::Acme(_) => {}
+                }
+                match tls::load_certificates(&config.tls).and_then(|c| tls::leaf_expiry(&c)) {
+                    Ok(expiry) if expiry.days_remaining < 0 => {
+                        println!("  └─ Expires: {} (expired)", expiry.not_after);
+                        let message = format!(
+                            "TLS certificate {} expired on {}",
+                            cert_path, expiry.not_after
+                        );
+                        issues.errors.push(message.clone());
+                        tls_error = Some(SnakeError::Tls(message));
+                    }
+                    Ok(expiry) => {
+                        println!("  └─ Expires: {} ({} days left)", expiry.not_after, expiry.days_remaining);
+                        if expiry.days_remaining <= i64::from(config.cert_expiry_warn_days) {
+                            issues.warnings.push(format!(
+                                "TLS certificate {} expires in {} day(s)",
+                                cert_path, expiry.days_remaining
+                            ));
+                        }
+                    }
+                    Err(e) => {
+                        println!("  └─ Expires: unknown");
+                        issues.warnings.push(format!("Could not read certificate expiry: {}", e));
+                    }
+                }
+            }
+            Err(e) => {
+                println!("  └─ ❌ {}", e);
+                issues.errors.push(format!("TLS configuration is invalid: {}", e));
+                tls_error = Some(e);
+            }
+        }
+    }
+
+    if !issues.warnings.is_empty() {
+        println!("\n⚠️  Warnings:");
+        for warning in &issues.warnings {
+            println!("  - {}", warning);
+        }
+    }
+    if !issues.errors.is_empty() {
+        println!("\n❌ Errors:");
+        for error in &issues.errors {
+            println!("  - {}", error);
+        }
+        // Exit with the TLS code when the certificate is the only problem
+        if issues.errors.len() == 1
+            && let Some(e) = tls_error
+        {
+            return Err(e);
+        }
+        return Err(SnakeError::Config(format!(
+            "Configuration has {} error(s)",
+            issues.errors.len()
+        )));
+    }
+
     println!("\n✅ Configuration is valid and ready to use");
     println!("\nMinimum requirements met:");
     println!("  ✓ At least 1 gateway configured ({} found)", config.gateways.len());
@@ -365,19 +1029,3 @@ fn check_config(config_path: &str) -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
-
-/// Load TLS configuration from certificate and private key files
-async fn load_tls_config(cert_path: &str, key_path: &str) -> Result<RustlsConfig, Box<dyn std::error::Error>> {
-    // Verify files exist before attempting to load
-    if !std::path::Path::new(cert_path).exists() {
-        return Err(format!("Certificate file not found: {}", cert_path).into());
-    }
-    if !std::path::Path::new(key_path).exists() {
-        return Err(format!("Private key file not found: {}", key_path).into());
-    }
-
-    // Use RustlsConfig::from_pem_file which handles certificate and key loading
-    RustlsConfig::from_pem_file(cert_path, key_path)
-        .await
-        .map_err(|e| format!("Failed to load TLS configuration: {}", e).into())
-}
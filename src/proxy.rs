@@ -1,20 +1,41 @@
-use crate::config::Config;
+use crate::access_log::{AccessEntry, AccessLog};
+use crate::admin;
+use crate::anthropic;
+use crate::config::{Config, GatewayAuthMode, GatewayConfig, ProviderSource};
+use crate::cost::{CostTracker, Usage};
+use crate::error::SnakeError;
 use crate::stream::convert_to_sse_stream;
 use axum::{
-    body::Body,
+    body::{Body, HttpBody},
     extract::State,
-    http::{Request, StatusCode},
+    http::{Extensions, HeaderMap, HeaderValue, Method, Request, StatusCode, header},
     response::{IntoResponse, Response},
 };
-use http_body_util::BodyExt;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
 use reqwest::Client;
 use serde_json::{Value, json};
-use tracing::{error, info};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast};
+use tracing::{Instrument, Span, error, info, info_span, warn};
+use uuid::Uuid;
 
 /// Custom error type for proxy operations
+#[derive(Clone)]
 pub enum ProxyError {
     BadRequest(String),
     BadGateway(String),
+    ServiceUnavailable(String),
+    NotFound(String),
+    Unauthorized(String),
+    Forbidden(String),
+    PayloadTooLarge(String),
 }
 
 impl IntoResponse for ProxyError {
@@ -28,6 +49,27 @@ impl IntoResponse for ProxyError {
                 error!("Bad Gateway: {}", msg);
                 (StatusCode::BAD_GATEWAY, msg)
             }
+            ProxyError::ServiceUnavailable(msg) => {
+                error!("Service Unavailable: {}", msg);
+                (StatusCode::SERVICE_UNAVAILABLE, msg)
+            }
+            // Mostly scanners probing random paths, so keep it out of the error log
+            ProxyError::NotFound(msg) => {
+                info!("Not Found: {}", msg);
+                (StatusCode::NOT_FOUND, msg)
+            }
+            ProxyError::Unauthorized(msg) => {
+                warn!("Unauthorized: {}", msg);
+                (StatusCode::UNAUTHORIZED, msg)
+            }
+            ProxyError::Forbidden(msg) => {
+                warn!("Forbidden: {}", msg);
+                (StatusCode::FORBIDDEN, msg)
+            }
+            ProxyError::PayloadTooLarge(msg) => {
+                error!("Payload Too Large: {}", msg);
+                (StatusCode::PAYLOAD_TOO_LARGE, msg)
+            }
         };
         (status, error_message).into_response()
     }
@@ -36,27 +78,279 @@ impl IntoResponse for ProxyError {
 /// Application state holding the HTTP client and configuration
 #[derive(Clone)]
 pub struct AppState {
+    /// Upstream client that decompresses responses, for the paths that parse the body
     pub client: Client,
-    pub config: Config,
+    /// Upstream client that leaves response bodies and `Content-Encoding` as upstream sent them
+    pub passthrough_client: Client,
+    /// Config this state was snapshotted with; handlers call `current` to pick up reloads
+    pub config: Arc<Config>,
+    /// Cached result of the upstream connectivity check, served by /readyz
+    pub ready: Arc<AtomicBool>,
+    /// Running cost totals computed from `[pricing]`
+    pub costs: Arc<CostTracker>,
+    /// Permits for in-flight upstream requests when `max_concurrent_upstream` is set
+    pub upstream_permits: Option<Arc<Semaphore>>,
+    /// Requests currently waiting for an upstream permit, for `queue_timeout_ms`
+    pub queued: Arc<AtomicUsize>,
+    /// Combined Log Format writer when `access_log` is set
+    pub access_log: Option<AccessLog>,
+    /// Deterministic requests currently upstream, keyed by request hash, for `coalesce_requests`
+    inflight: Arc<Mutex<HashMap<String, broadcast::Sender<SharedReply>>>>,
+    /// Latest config, replaced by `serve --watch` reloads
+    live_config: Arc<RwLock<Arc<Config>>>,
+}
+
+impl AppState {
+    /// Create the shared state; readiness starts true since startup already checked connectivity
+    pub fn new(client: Client, passthrough_client: Client, config: Config) -> Self {
+        let upstream_permits = config
+            .max_concurrent_upstream
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        let config = Arc::new(config);
+        Self {
+            client,
+            passthrough_client,
+            live_config: Arc::new(RwLock::new(config.clone())),
+            config,
+            ready: Arc::new(AtomicBool::new(true)),
+            costs: Arc::new(CostTracker::default()),
+            upstream_permits,
+            queued: Arc::default(),
+            access_log: None,
+            inflight: Arc::default(),
+        }
+    }
+
+    /// This state with the most recently loaded config, for use by one request
+    pub fn current(&self) -> Self {
+        let config = self
+            .live_config
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        Self {
+            config,
+            ..self.clone()
+        }
+    }
+
+    /// Swap in a reloaded config; requests already in flight keep the one they started with
+    pub fn replace_config(&self, config: Config) {
+        *self.live_config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+    }
+}
+
+/// Start building an upstream HTTP client with the configured client certificate and proxy.
+/// Without `upstream_proxy`, reqwest falls back to the HTTPS_PROXY/HTTP_PROXY/ALL_PROXY variables.
+pub fn upstream_client_builder(config: &Config) -> Result<reqwest::ClientBuilder, SnakeError> {
+    let pool = &config.http_client;
+    let mut builder = Client::builder()
+        .pool_idle_timeout(Duration::from_millis(pool.pool_idle_timeout_ms))
+        .tcp_keepalive(
+            Some(Duration::from_millis(pool.tcp_keepalive_ms)).filter(|d| !d.is_zero()),
+        );
+    if let Some(max_idle) = pool.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(proxy_url) = &config.upstream_proxy {
+        let proxy = reqwest::Proxy::all(proxy_url)
+            .map_err(|e| {
+                SnakeError::Config(format!("Invalid upstream_proxy '{}': {}", proxy_url, e))
+            })?;
+        builder = builder.proxy(proxy);
+        info!("Routing upstream traffic through proxy: {}", proxy_url);
+    }
+    if let (Some(cert_path), Some(key_path)) =
+        (&config.upstream_client_cert, &config.upstream_client_key)
+    {
+        let identity = load_client_identity(cert_path, key_path).map_err(SnakeError::Tls)?;
+        builder = builder.identity(identity);
+        info!("Using upstream client certificate: {}", cert_path);
+    }
+    Ok(builder)
+}
+
+/// Build the upstream client for responses forwarded unparsed: without reqwest's automatic
+/// gzip/brotli/deflate decoding, so the client receives the body and `Content-Encoding` as sent
+pub fn passthrough_client(config: &Config) -> Result<Client, SnakeError> {
+    Ok(upstream_client_builder(config)?
+        .no_gzip()
+        .no_brotli()
+        .no_deflate()
+        .build()?)
+}
+
+/// Load a PEM client certificate and private key into a reqwest identity
+fn load_client_identity(cert_path: &str, key_path: &str) -> Result<reqwest::Identity, String> {
+    // Verify files exist before attempting to load
+    if !std::path::Path::new(cert_path).exists() {
+        return Err(format!("Upstream client certificate not found: {}", cert_path));
+    }
+    if !std::path::Path::new(key_path).exists() {
+        return Err(format!("Upstream client key not found: {}", key_path));
+    }
+
+    let mut pem = std::fs::read(cert_path)
+        .map_err(|e| format!("Failed to read upstream client certificate {}: {}", cert_path, e))?;
+    let key = std::fs::read(key_path)
+        .map_err(|e| format!("Failed to read upstream client key {}: {}", key_path, e))?;
+    pem.push(b'\n');
+    pem.extend_from_slice(&key);
+
+    reqwest::Identity::from_pem(&pem).map_err(|e| {
+        // reqwest only says "builder error", the underlying cause is in the source
+        let cause = std::error::Error::source(&e)
+            .map(|source| source.to_string())
+            .unwrap_or_else(|| e.to_string());
+        format!("Invalid upstream client certificate or key: {}", cause)
+    })
 }
 
+/// Responses smaller than this are sent uncompressed; gzip overhead outweighs the savings
+const MIN_COMPRESS_BYTES: usize = 1024;
+
+/// Client path of OpenAI chat completions, also the target of translated Messages requests
+const CHAT_COMPLETIONS_PATH: &str = "/v1/chat/completions";
+/// Every upstream chat completions endpoint ends with this, compat and provider-native alike
+const CHAT_COMPLETIONS_SUFFIX: &str = "/chat/completions";
+
+/// Header authenticating the request to the Cloudflare AI Gateway
+const GATEWAY_AUTH_HEADER: &str = "cf-aig-authorization";
+
+/// Header carrying the per-request correlation id
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Response header naming the gateway that served the request; as a request header
+/// from a local client it pins the request to that gateway
+pub const GATEWAY_HEADER: &str = "x-snake-gateway";
+
+/// Response header naming the `[fallback]` model that answered after the requested one failed
+const FALLBACK_HEADER: &str = "x-snake-fallback";
+
+/// Response header with the milliseconds spent sending the upstream request and reading its body
+const UPSTREAM_MS_HEADER: &str = "x-snake-upstream-ms";
+
+/// Request header naming the provider when `provider_source = "header"`
+const PROVIDER_HEADER: &str = "x-provider";
+
 /// Main proxy handler that forwards requests to Cloudflare AI Gateway
-pub async fn proxy_handler(
-    State(state): State<AppState>,
+pub async fn proxy_handler(State(state): State<AppState>, req: Request<Body>) -> Response {
+    let state = state.current();
+    // Reuse the client's request id when present so logs line up across the stack
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let span = info_span!(
+        "request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = %req.uri().path(),
+        gateway = tracing::field::Empty,
+        status = tracing::field::Empty,
+        elapsed_ms = tracing::field::Empty,
+    );
+
+    // Request line and client details are captured before the request is consumed
+    let access = state
+        .access_log
+        .clone()
+        .map(|log| (log, AccessEntry::from_request(&req, &request_id)));
+
+    let started = Instant::now();
+    let mut response = forward_request(state, req, &request_id)
+        .instrument(span.clone())
+        .await
+        .into_response();
+
+    let elapsed_ms = started.elapsed().as_millis() as u64;
+    span.record("status", response.status().as_u16());
+    span.record("elapsed_ms", elapsed_ms);
+    span.in_scope(|| info!("Request completed"));
+
+    if let Some((log, mut entry)) = access {
+        entry.status = response.status().as_u16();
+        entry.bytes = response.body().size_hint().exact();
+        entry.elapsed_ms = elapsed_ms;
+        log.record(&entry);
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Forward a single request upstream and build the client response
+async fn forward_request(
+    state: AppState,
     req: Request<Body>,
+    request_id: &str,
 ) -> Result<Response, ProxyError> {
     let (parts, body) = req.into_parts();
     let method = parts.method;
     let headers = parts.headers;
+    let path = state.config.strip_route_prefix(parts.uri.path());
+    if !state.config.path_allowed(path) {
+        return Err(ProxyError::NotFound(format!(
+            "Path {} is not proxied (see allowed_paths)",
+            path
+        )));
+    }
+    if let Some(response) = preflight_response(&state.config, &method, &headers) {
+        return Ok(response);
+    }
+    if state.config.gateway_auth_mode == GatewayAuthMode::Require
+        && !headers.contains_key(GATEWAY_AUTH_HEADER)
+    {
+        return Err(ProxyError::Unauthorized(
+            "Missing cf-aig-authorization header (gateway_auth_mode = \"require\")".to_string(),
+        ));
+    }
+    // Anthropic Messages requests are translated and sent to the chat completions endpoint
+    let anthropic_request =
+        state.config.translation.anthropic && anthropic::is_messages_path(path);
+    let mut upstream_path = if anthropic_request {
+        state.config.upstream_path(CHAT_COMPLETIONS_PATH)
+    } else {
+        state.config.upstream_path(path)
+    };
+    // Keyed off the upstream endpoint, so aliases such as `/chat/completions`, a bare `/` or
+    // a provider-native `/openai/chat/completions` count too
+    let chat_completions = upstream_path.ends_with(CHAT_COMPLETIONS_SUFFIX);
+    // Keep the original query string, some endpoints need parameters like ?beta=true
+    if let Some(query) = parts.uri.query() {
+        upstream_path.push('?');
+        upstream_path.push_str(query);
+    }
 
-    // Get the next gateway in round-robin fashion
-    let target_url = state.config.next_target_url();
-    let gateway_token = state.config.current_gateway_token();
+    // Get the pinned gateway, the session's gateway, or the next one in round-robin fashion
+    let pinned_index = pinned_gateway(&state.config, &headers, &parts.extensions)?;
+    let sticky_index = match pinned_index {
+        Some(_) => None,
+        None => sticky_gateway(&state.config, &headers),
+    };
+    let fixed_index = pinned_index.or(sticky_index);
+    let gateway_index = match fixed_index {
+        Some(index) => index,
+        None => next_gateway(&state.config)?,
+    };
+    let gateway = &state.config.gateways[gateway_index];
+    let target_url = state.config.target_url(gateway, &upstream_path);
+    Span::current().record("gateway", gateway_index);
 
-    info!("Forwarding request to: {} {} (round-robin)", method, target_url);
+    let selection = match (pinned_index, sticky_index) {
+        (Some(_), _) => "pinned",
+        (_, Some(_)) => "sticky",
+        _ => "round-robin",
+    };
+    info!("Forwarding request to: {} {} ({})", method, target_url, selection);
 
     // Log headers for debugging
-    if let Some(cf_aig_auth) = headers.get("cf-aig-authorization") {
+    if let Some(cf_aig_auth) = headers.get(GATEWAY_AUTH_HEADER) {
         info!("Found cf-aig-authorization header: {:?}", cf_aig_auth);
     } else {
         info!("cf-aig-authorization header not found");
@@ -67,44 +361,165 @@ pub async fn proxy_handler(
         info!("authorization header not found");
     }
 
-    // Read the request body
-    let full_body = body
+    // Read the request body, refusing to buffer more than max_body_bytes
+    let max_body_bytes = state.config.max_body_bytes;
+    let full_body = Limited::new(body, max_body_bytes)
         .collect()
         .await
-        .map_err(|e| ProxyError::BadRequest(format!("Failed to read request body: {}", e)))?;
+        .map_err(|e| {
+            if e.is::<LengthLimitError>() {
+                ProxyError::PayloadTooLarge(format!(
+                    "Request body exceeds the {} byte limit",
+                    max_body_bytes
+                ))
+            } else {
+                ProxyError::BadRequest(format!("Failed to read request body: {}", e))
+            }
+        })?;
     let body_bytes = full_body.to_bytes();
 
+    // Multipart uploads (e.g. audio transcriptions) must keep their exact bytes and boundary
+    let is_multipart = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| {
+            ct.trim_start()
+                .to_ascii_lowercase()
+                .starts_with("multipart/form-data")
+        });
+
     // Try to parse the body as JSON and check for stream parameter
     let mut provider_name: Option<String> = None;
-    let (modified_body, was_stream_request) =
-        if let Ok(mut json_body) = serde_json::from_slice::<Value>(&body_bytes) {
-            // Extract provider name from model field (format: "provider/model_name")
-            if let Some(model) = json_body.get("model").and_then(|v| v.as_str()) {
-                if let Some(provider) = model.split('/').next() {
-                    provider_name = Some(provider.to_string());
-                    info!("Detected provider from model: {}", provider);
-                }
-            }
-
-            let was_stream = json_body
-                .get("stream")
-                .and_then(|v| v.as_bool())
-                .unwrap_or(false);
-
-            if was_stream {
-                info!("Detected stream request, converting to non-stream for Cloudflare");
-                json_body["stream"] = json!(false);
-                let modified = serde_json::to_vec(&json_body).map_err(|e| {
-                    ProxyError::BadRequest(format!("Failed to serialize modified body: {}", e))
-                })?;
-                (modified, true)
-            } else {
-                (body_bytes.to_vec(), false)
+    if state.config.provider_source == ProviderSource::Header {
+        provider_name = headers
+            .get(PROVIDER_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_string);
+        if let Some(provider) = &provider_name {
+            info!("Detected provider from {} header: {}", PROVIDER_HEADER, provider);
+        }
+    }
+    let mut model_name: Option<String> = None;
+    let mut deterministic = false;
+    // Kept only when the model has a fallback chain, to rebuild the body with another model
+    let mut fallback_body: Option<Value> = None;
+    // Local reply for `[dev] echo`, built from the request instead of calling upstream
+    let mut echo_reply: Option<Value> = None;
+    let (modified_body, was_stream_request) = if is_multipart {
+        info!("Detected multipart/form-data request, forwarding body untouched");
+        (body_bytes.to_vec(), false)
+    } else if let Ok(mut json_body) = serde_json::from_slice::<Value>(&body_bytes) {
+        if anthropic_request {
+            json_body = anthropic::request_to_openai(&json_body)
+                .map_err(|e| ProxyError::BadRequest(format!("Invalid Messages request: {}", e)))?;
+            info!("Translated Anthropic Messages request to chat completions");
+        }
+        let defaulted = state.config.apply_defaults(&mut json_body);
+        // Only chat completions are given a limit they didn't ask for; other endpoints may
+        // reject the field
+        let clamped = state.config.enforce_max_tokens(&mut json_body, chat_completions);
+
+        deterministic = json_body.get("temperature").and_then(|t| t.as_f64()) == Some(0.0);
+
+        // The `provider` field is ours, not an upstream parameter, so it is never forwarded
+        let provider_field = match state.config.provider_source {
+            ProviderSource::Body => json_body
+                .as_object_mut()
+                .and_then(|body| body.remove("provider")),
+            _ => None,
+        };
+        if let Some(provider) = provider_field.as_ref().and_then(|p| p.as_str()) {
+            provider_name = Some(provider.to_string());
+            info!("Detected provider from body field: {}", provider);
+        }
+
+        // Extract provider name from model field (format: "provider/model_name"), unless the
+        // configured provider_source already named one
+        if let Some(model) = json_body.get("model").and_then(|v| v.as_str()) {
+            model_name = Some(model.to_string());
+            if provider_name.is_none()
+                && let Some(provider) = model.split('/').next()
+            {
+                provider_name = Some(provider.to_string());
+                info!("Detected provider from model: {}", provider);
             }
+        }
+
+        // An explicit `stream` field wins; without one, `Accept: text/event-stream` asks for SSE
+        let was_stream = json_body
+            .get("stream")
+            .and_then(|v| v.as_bool())
+            .unwrap_or_else(|| accepts_event_stream(&headers));
+
+        if was_stream {
+            info!("Detected stream request, converting to non-stream for Cloudflare");
+            json_body["stream"] = json!(false);
+        }
+        if state.config.dev.echo {
+            echo_reply = Some(echo_completion(&json_body));
+        }
+        if model_name
+            .as_deref()
+            .is_some_and(|model| !state.config.fallback_models(model).is_empty())
+        {
+            fallback_body = Some(json_body.clone());
+        }
+        let transformed = provider_name
+            .as_deref()
+            .is_some_and(|provider| state.config.transform_request(provider, &mut json_body));
+        if was_stream
+            || anthropic_request
+            || defaulted
+            || clamped
+            || transformed
+            || provider_field.is_some()
+        {
+            let modified = serde_json::to_vec(&json_body).map_err(|e| {
+                ProxyError::BadRequest(format!("Failed to serialize modified body: {}", e))
+            })?;
+            (modified, was_stream)
         } else {
-            // Not a JSON body or parsing failed, use as-is
             (body_bytes.to_vec(), false)
-        };
+        }
+    } else if anthropic_request {
+        return Err(ProxyError::BadRequest(
+            "Invalid Messages request: body must be JSON".to_string(),
+        ));
+    } else if state.config.strict_json && chat_completions {
+        let reason = serde_json::from_slice::<Value>(&body_bytes)
+            .err()
+            .map_or_else(String::new, |e| format!(": {}", e));
+        return Err(ProxyError::BadRequest(format!(
+            "Invalid chat completions request: body must be JSON{}",
+            reason
+        )));
+    } else {
+        // Not a JSON body or parsing failed, use as-is
+        (body_bytes.to_vec(), false)
+    };
+
+    // `[policy]` is checked before anything is sent upstream, or answered locally
+    if let Some(reason) = model_name
+        .as_deref()
+        .and_then(|model| state.config.policy.refusal(model))
+    {
+        return Err(ProxyError::Forbidden(reason));
+    }
+
+    if let Some(completion) = echo_reply {
+        info!("[dev] echo is enabled, answering locally without calling upstream");
+        let status = StatusCode::OK;
+        let bytes = bytes::Bytes::from(completion.to_string());
+        return Ok(if anthropic_request {
+            anthropic::translate_response(status, &bytes, was_stream_request)
+        } else if was_stream_request {
+            convert_to_sse_stream(status, bytes)
+        } else {
+            (status, [(header::CONTENT_TYPE, "application/json")], bytes).into_response()
+        });
+    }
 
     // Send request to Cloudflare
     // Filter out hop-by-hop headers and headers that reqwest will set automatically
@@ -119,31 +534,39 @@ pub async fn proxy_handler(
     filtered_headers.remove("trailers");
     filtered_headers.remove("transfer-encoding");
     filtered_headers.remove("upgrade");
+    filtered_headers.remove(GATEWAY_HEADER);
+    if state.config.provider_source == ProviderSource::Header {
+        filtered_headers.remove(PROVIDER_HEADER);
+    }
 
-    // Set the gateway token for authentication
-    let token_value = format!("Bearer {}", gateway_token);
+    // Apply the configured [headers] strip/add rules
+    state.config.header_rules.apply(&mut filtered_headers);
+
+    // The fake-stream and translation paths have to parse the upstream JSON, so only accept
+    // encodings reqwest decompresses for us (it strips content-encoding after decoding)
+    if was_stream_request || anthropic_request {
+        filtered_headers.insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, br, deflate"),
+        );
+    }
+    // The upstream call is non-streaming, so ask for JSON even if the client accepts SSE
+    if was_stream_request {
+        filtered_headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+    }
+
+    // Forward the request id so it shows up in Cloudflare logs too
     filtered_headers.insert(
-        "cf-aig-authorization",
-        token_value.parse().map_err(|e| {
-            ProxyError::BadRequest(format!("Invalid gateway token format: {}", e))
-        })?,
+        REQUEST_ID_HEADER,
+        HeaderValue::from_str(request_id)
+            .map_err(|e| ProxyError::BadRequest(format!("Invalid request id: {}", e)))?,
     );
 
-    // Use round-robin API key if provider is detected and multiple keys are configured
-    if let Some(provider) = provider_name {
-        if let Some(api_key) = state.config.next_api_key(&provider) {
-            info!("Using round-robin API key for provider: {}", provider);
-            let auth_value = format!("Bearer {}", api_key);
-            filtered_headers.insert(
-                "authorization",
-                auth_value.parse().map_err(|e| {
-                    ProxyError::BadRequest(format!("Invalid API key format: {}", e))
-                })?,
-            );
-        } else {
-            info!("No API key configured for provider: {}, using client's key", provider);
-        }
-    }
+    // Fallback attempts start from the client's own credentials, not the primary provider's key
+    let fallback_headers = fallback_body.as_ref().map(|_| filtered_headers.clone());
+
+    // Use round-robin API key if the detected provider has server-managed keys
+    let managed_key = apply_managed_key(&state, &mut filtered_headers, provider_name)?;
 
     info!("Sending request to Cloudflare...");
     if was_stream_request {
@@ -152,35 +575,187 @@ pub async fn proxy_handler(
             modified_body.len()
         );
     }
-    let client_request = state
-        .client
-        .request(method, &target_url)
-        .headers(filtered_headers)
-        .body(modified_body);
-    let response = client_request.send().await.map_err(|e| {
-        error!("Failed to forward request to Cloudflare: {}", e);
-        ProxyError::BadGateway(format!("Failed to forward request to target: {}", e))
-    })?;
+    let upstream = UpstreamRequest {
+        method,
+        path: upstream_path,
+        headers: filtered_headers,
+        body: bytes::Bytes::from(modified_body),
+        decode: was_stream_request
+            || anthropic_request
+            || state.config.normalize_errors
+            || managed_key.is_some()
+            || model_name
+                .as_deref()
+                .is_some_and(|model| state.config.pricing_for(model).is_some()),
+    };
 
-    info!(
-        "Received response from Cloudflare, status: {}",
-        response.status()
+    // Mirror a sampled share of traffic to the shadow gateway; its response is never returned
+    if let Some(shadow) = &state.config.shadow
+        && rand::random::<f64>() * 100.0 < shadow.percent
+    {
+        let shadow_gateway = shadow.gateway_for(gateway);
+        match upstream.build(&state, &shadow_gateway) {
+            Ok(shadow_request) => spawn_shadow_request(shadow_gateway.gateway_id, shadow_request),
+            Err(_) => error!(
+                "Invalid shadow gateway token format for gateway {}",
+                shadow_gateway.gateway_id
+            ),
+        }
+    }
+
+    // Hedging duplicates the request, so only use it for non-streaming calls that aren't pinned
+    // or sticky and have a second gateway
+    let hedge_delay = state
+        .config
+        .hedging
+        .as_ref()
+        .filter(|h| {
+            h.enabled
+                && !was_stream_request
+                && fixed_index.is_none()
+                && state.config.gateways.len() > 1
+        })
+        .map(|h| Duration::from_millis(h.delay_ms));
+
+    // Identical deterministic requests share one upstream call when coalescing is enabled
+    let coalesce_key = (state.config.coalesce_requests && deterministic)
+        .then(|| coalesce_key(&headers, &upstream));
+
+    let send = send_upstream(
+        &state,
+        &upstream,
+        gateway_index,
+        hedge_delay,
+        managed_key.as_ref(),
     );
+    let (mut reply, mut coalesced) = match &coalesce_key {
+        Some(key) => coalesce(&state, key, send).await,
+        None => (send.await, false),
+    };
 
-    let status = response.status();
-    let response_headers = response.headers().clone();
+    // Retry through the rotation with each fallback model until one doesn't fail
+    let mut fallback_model = None;
+    if let (Some(json_body), Some(mut headers), Some(model)) =
+        (fallback_body, fallback_headers, model_name.clone())
+    {
+        let mut failed_model = model.clone();
+        for fallback in state.config.fallback_models(&model) {
+            if !should_fall_back(&reply) {
+                break;
+            }
+            // `[policy]` applies to fallbacks too, a refused one is passed over
+            if let Some(reason) = state.config.policy.refusal(fallback) {
+                warn!("Skipping fallback model {}: {}", fallback, reason);
+                continue;
+            }
+            warn!("Model {} failed, retrying with fallback model {}", failed_model, fallback);
+            let provider = fallback.split('/').next().map(str::to_string);
+            let mut attempt_body = json_body.clone();
+            attempt_body["model"] = json!(fallback);
+            if let Some(provider) = &provider {
+                state.config.transform_request(provider, &mut attempt_body);
+            }
+            let body = serde_json::to_vec(&attempt_body).map_err(|e| {
+                ProxyError::BadRequest(format!("Failed to serialize fallback body: {}", e))
+            })?;
+            let managed_key = apply_managed_key(&state, &mut headers, provider)?;
+            let attempt = UpstreamRequest {
+                method: upstream.method.clone(),
+                path: upstream.path.clone(),
+                headers: headers.clone(),
+                body: bytes::Bytes::from(body),
+                decode: upstream.decode || managed_key.is_some(),
+            };
+            let index = match fixed_index {
+                Some(index) => index,
+                None => next_gateway(&state.config)?,
+            };
+            reply = send_upstream(&state, &attempt, index, None, managed_key.as_ref()).await;
+            coalesced = false;
+            failed_model = fallback.clone();
+            fallback_model = Some(fallback.clone());
+        }
+        if fallback_model.is_some() {
+            model_name = fallback_model.clone();
+        }
+    }
 
-    let bytes = response.bytes().await.map_err(|e| {
-        error!("Failed to read response body from Cloudflare: {}", e);
-        ProxyError::BadGateway(format!("Failed to read response body: {}", e))
-    })?;
+    let UpstreamReply {
+        served_by,
+        status,
+        headers: mut response_headers,
+        body: mut bytes,
+        upstream_ms,
+    } = reply?;
 
-    info!("Read response body, {} bytes", bytes.len());
+    // A coalesced reply was already costed by the request that made the upstream call
+    if status.is_success()
+        && !coalesced
+        && let Some(model) = &model_name
+    {
+        record_cost(&state, model, &bytes);
+    }
+
+    // Optionally rewrite non-2xx bodies into the OpenAI error envelope clients expect
+    if state.config.normalize_errors
+        && !status.is_success()
+        && let Some(normalized) = normalize_error_body(status, &bytes)
+    {
+        info!("Normalized upstream error body into OpenAI error format");
+        bytes = normalized;
+        response_headers.remove("content-length");
+        response_headers.remove("content-encoding");
+        response_headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+    }
+
+    // Headers telling the client which gateway, and which fallback model if any, answered, and
+    // how long the upstream call took
+    let mut snake_headers = HeaderMap::new();
+    if let Ok(gateway_id) = HeaderValue::from_str(&state.config.gateways[served_by].gateway_id) {
+        snake_headers.insert(GATEWAY_HEADER, gateway_id);
+    }
+    if let Some(model) = &fallback_model
+        && let Ok(value) = HeaderValue::from_str(model)
+    {
+        snake_headers.insert(FALLBACK_HEADER, value);
+    }
+    snake_headers.insert(UPSTREAM_MS_HEADER, HeaderValue::from(upstream_ms));
+
+    if (was_stream_request || anthropic_request)
+        && let Some(encoding) = response_headers.get(header::CONTENT_ENCODING)
+        && encoding != "identity"
+    {
+        return Err(ProxyError::BadGateway(format!(
+            "Cannot convert upstream response with content-encoding {:?}",
+            encoding
+        )));
+    }
+
+    // Translate back to the Messages format, streaming if the client asked for it
+    if anthropic_request {
+        info!("Translating response to Anthropic Messages format");
+        let mut translated = anthropic::translate_response(status, &bytes, was_stream_request);
+        translated.headers_mut().extend(snake_headers);
+        state
+            .config
+            .response_header_rules
+            .add_to(translated.headers_mut());
+        return Ok(translated);
+    }
 
     // If the original request wanted streaming, convert the response to SSE format
     if was_stream_request {
         info!("Converting response to SSE stream format");
-        return Ok(convert_to_sse_stream(status, bytes));
+        let mut sse_response = convert_to_sse_stream(status, bytes);
+        sse_response.headers_mut().extend(snake_headers);
+        state
+            .config
+            .response_header_rules
+            .add_to(sse_response.headers_mut());
+        return Ok(sse_response);
     }
 
     // Otherwise, return the response as-is
@@ -196,6 +771,32 @@ pub async fn proxy_handler(
     filtered_response_headers.remove("trailers");
     filtered_response_headers.remove("transfer-encoding");
     filtered_response_headers.remove("upgrade");
+    filtered_response_headers.extend(snake_headers);
+
+    // Apply the configured [response_headers] strip/add rules
+    state
+        .config
+        .response_header_rules
+        .apply(&mut filtered_response_headers);
+
+    if state.config.compress_responses
+        && bytes.len() >= MIN_COMPRESS_BYTES
+        && accepts_gzip(&headers)
+        && !filtered_response_headers.contains_key(header::CONTENT_ENCODING)
+    {
+        match gzip(&bytes) {
+            Ok(compressed) => {
+                info!("Compressed response body {} -> {} bytes", bytes.len(), compressed.len());
+                bytes = compressed;
+                filtered_response_headers.remove(header::CONTENT_LENGTH);
+                filtered_response_headers
+                    .insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                filtered_response_headers
+                    .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+            }
+            Err(e) => warn!("Failed to compress response body, sending it uncompressed: {}", e),
+        }
+    }
 
     let mut axum_res = Response::new(Body::from(bytes));
     *axum_res.status_mut() = status;
@@ -203,3 +804,852 @@ pub async fn proxy_handler(
 
     Ok(axum_res)
 }
+
+/// Whether the client's `Accept-Encoding` allows gzip (`q=0` refuses it)
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let refused = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+fn gzip(body: &[u8]) -> std::io::Result<bytes::Bytes> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), Compression::fast());
+    encoder.write_all(body)?;
+    Ok(bytes::Bytes::from(encoder.finish()?))
+}
+
+/// Compute the request cost from the response `usage` object and add it to the running totals
+fn record_cost(state: &AppState, model: &str, body: &[u8]) {
+    let Some(pricing) = state.config.pricing_for(model) else {
+        return;
+    };
+    let Some(usage) = Usage::from_response(body) else {
+        return;
+    };
+    let cost = usage.cost(pricing);
+    let total = state.costs.record(model, usage, cost);
+    info!(
+        "Request cost: model={} input_tokens={} output_tokens={} cost=${:.6} (running total ${:.6})",
+        model, usage.input_tokens, usage.output_tokens, cost, total
+    );
+}
+
+/// A prepared upstream request that can be sent to any gateway
+struct UpstreamRequest {
+    method: Method,
+    /// Path and query string appended to the gateway base URL
+    path: String,
+    headers: HeaderMap,
+    body: bytes::Bytes,
+    /// Whether the proxy parses the response (SSE conversion, translation, costs or error
+    /// normalization), so it has to arrive decompressed
+    decode: bool,
+}
+
+impl UpstreamRequest {
+    /// Build the request for a gateway, authenticating with that gateway's token unless
+    /// `gateway_auth_mode` forwards the client's own header
+    fn build(
+        &self,
+        state: &AppState,
+        gateway: &GatewayConfig,
+    ) -> Result<reqwest::RequestBuilder, ProxyError> {
+        let mut headers = self.headers.clone();
+        let client_auth = state.config.gateway_auth_mode != GatewayAuthMode::Override
+            && headers.contains_key(GATEWAY_AUTH_HEADER);
+        if !client_auth {
+            headers.insert(GATEWAY_AUTH_HEADER, gateway_auth_value(&gateway.token)?);
+        }
+        // Validated at config load
+        if let Some(host) = &gateway.host_header
+            && let Ok(value) = HeaderValue::from_str(host)
+        {
+            headers.insert(header::HOST, value);
+        }
+        let client = if self.decode {
+            &state.client
+        } else {
+            &state.passthrough_client
+        };
+        Ok(client
+            .request(self.method.clone(), state.config.target_url(gateway, &self.path))
+            .headers(headers)
+            .body(self.body.clone()))
+    }
+}
+
+/// Whether the client's `Accept` header asks for a server-sent event stream
+fn accepts_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|media_type| {
+            media_type
+                .split(';')
+                .next()
+                .is_some_and(|t| t.trim().eq_ignore_ascii_case("text/event-stream"))
+        })
+}
+
+/// Answer a CORS preflight locally, reflecting the requested method and headers. Returns `None`
+/// for anything that isn't a preflight, or when `[preflight]` is disabled, so it is forwarded.
+fn preflight_response(config: &Config, method: &Method, headers: &HeaderMap) -> Option<Response> {
+    let preflight = &config.preflight;
+    if !preflight.enabled || method != Method::OPTIONS {
+        return None;
+    }
+    let origin = headers.get(header::ORIGIN)?;
+    let requested_method = headers.get(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    let response_headers = response.headers_mut();
+    response_headers.insert(
+        header::VARY,
+        HeaderValue::from_static(
+            "origin, access-control-request-method, access-control-request-headers",
+        ),
+    );
+    if !origin.to_str().is_ok_and(|o| preflight.allows_origin(o)) {
+        warn!("Rejected CORS preflight from origin {:?}", origin);
+        return Some(response);
+    }
+
+    info!("Answering CORS preflight from origin {:?} locally", origin);
+    response_headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+    response_headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, requested_method.clone());
+    if let Some(requested_headers) = headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+        response_headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested_headers.clone());
+    }
+    response_headers.insert(
+        header::ACCESS_CONTROL_MAX_AGE,
+        HeaderValue::from(preflight.max_age_secs),
+    );
+    Some(response)
+}
+
+/// Set the upstream `Authorization` header to the provider's next server-managed key, if it has
+/// any. Returns the provider and key index so a rejected key can be quarantined.
+fn apply_managed_key(
+    state: &AppState,
+    headers: &mut HeaderMap,
+    provider: Option<String>,
+) -> Result<Option<(String, usize)>, ProxyError> {
+    let Some(provider) = provider else {
+        return Ok(None);
+    };
+    let Some((key_index, api_key)) = state.config.next_api_key(&provider) else {
+        info!("No server-managed keys for provider: {}, using client's key", provider);
+        return Ok(None);
+    };
+    info!("Using round-robin API key for provider: {}", provider);
+    let auth_value = format!("Bearer {}", api_key);
+    headers.insert(
+        "authorization",
+        auth_value
+            .parse()
+            .map_err(|e| ProxyError::BadRequest(format!("Invalid API key format: {}", e)))?,
+    );
+    Ok(Some((provider, key_index)))
+}
+
+/// Whether a reply failed in a way a fallback model could fix: a 5xx or no response at all
+fn should_fall_back(reply: &SharedReply) -> bool {
+    match reply {
+        Ok(reply) => reply.status.is_server_error(),
+        Err(ProxyError::BadGateway(_)) => true,
+        Err(_) => false,
+    }
+}
+
+/// A fully read upstream response
+#[derive(Clone)]
+struct UpstreamReply {
+    /// Index of the gateway whose response this is
+    served_by: usize,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: bytes::Bytes,
+    /// Time from sending the request until the whole body was read
+    upstream_ms: u64,
+}
+
+/// Outcome of an upstream call, shared with coalesced requests
+type SharedReply = Result<UpstreamReply, ProxyError>;
+
+/// Send the request, hedged when configured, and read the whole upstream response
+async fn send_upstream(
+    state: &AppState,
+    upstream: &UpstreamRequest,
+    gateway_index: usize,
+    hedge_delay: Option<Duration>,
+    managed_key: Option<&(String, usize)>,
+) -> SharedReply {
+    // Held until the upstream body has been read; saturation is reported as 503
+    let _permit = match &state.upstream_permits {
+        Some(permits) => Some(acquire_permit(state, permits).await?),
+        None => None,
+    };
+
+    let client_request = upstream.build(state, &state.config.gateways[gateway_index])?;
+    state.config.record_gateway_request(gateway_index, upstream.body.len());
+    let sent = Instant::now();
+    let (served_by, result) = match hedge_delay {
+        Some(delay) => send_hedged(state, gateway_index, client_request, upstream, delay).await?,
+        None => (gateway_index, client_request.send().await),
+    };
+    let response = result.map_err(|e| {
+        state.config.record_gateway_result(served_by, false);
+        forward_error(e)
+    })?;
+    state
+        .config
+        .record_gateway_result(served_by, response.status().is_success());
+
+    info!(
+        "Received response from Cloudflare, status: {}",
+        response.status()
+    );
+
+    let status = response.status();
+    let headers = response.headers().clone();
+
+    let body = response.bytes().await.map_err(|e| {
+        error!("Failed to read response body from Cloudflare: {}", e);
+        ProxyError::BadGateway(format!("Failed to read response body: {}", e))
+    })?;
+
+    info!("Read response body, {} bytes", body.len());
+    if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+        && let Some((provider, key_index)) = managed_key
+    {
+        if provider_rejected_key(&body) {
+            state.config.quarantine_api_key(provider, *key_index);
+        } else {
+            warn!(
+                "Auth failure for provider '{}' came from the gateway, not the provider; keeping its API key",
+                provider
+            );
+        }
+    }
+    state
+        .config
+        .record_gateway_response_bytes(served_by, body.len());
+
+    Ok(UpstreamReply {
+        served_by,
+        status,
+        headers,
+        body,
+        upstream_ms: sent.elapsed().as_millis() as u64,
+    })
+}
+
+/// Whether a 401/403 came from the provider rejecting its key rather than from Cloudflare
+/// rejecting the gateway token. Cloudflare answers in its API envelope
+/// (`{"success":false,"error":[...]}`); providers answer in their own JSON error format.
+fn provider_rejected_key(body: &[u8]) -> bool {
+    serde_json::from_slice::<Value>(body)
+        .is_ok_and(|json| json.is_object() && json.get("success").is_none())
+}
+
+/// Hash of everything that makes two requests interchangeable. The client's credentials are
+/// included so a reply is only shared between callers presenting the same keys.
+fn coalesce_key(client_headers: &HeaderMap, upstream: &UpstreamRequest) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(upstream.method.as_str());
+    hasher.update([0]);
+    hasher.update(&upstream.path);
+    for name in ["authorization", "cf-aig-authorization"] {
+        hasher.update([0]);
+        if let Some(value) = client_headers.get(name) {
+            hasher.update(value.as_bytes());
+        }
+    }
+    hasher.update([0]);
+    hasher.update(&upstream.body);
+    hasher.update([upstream.decode as u8]);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Take an upstream permit, waiting up to `queue_timeout` for one when all are in use
+async fn acquire_permit(
+    state: &AppState,
+    permits: &Arc<Semaphore>,
+) -> Result<OwnedSemaphorePermit, ProxyError> {
+    let saturated = || {
+        ProxyError::ServiceUnavailable(
+            "Too many concurrent upstream requests, try again later".to_string(),
+        )
+    };
+    if let Ok(permit) = permits.clone().try_acquire_owned() {
+        return Ok(permit);
+    }
+    let queue_timeout = state.config.queue_timeout;
+    if queue_timeout.is_zero() {
+        return Err(saturated());
+    }
+
+    let _slot = QueueSlot::join(&state.queued);
+    match tokio::time::timeout(queue_timeout, permits.clone().acquire_owned()).await {
+        Ok(Ok(permit)) => Ok(permit),
+        _ => Err(saturated()),
+    }
+}
+
+/// Counts a request in the upstream queue until it gets a permit, times out or is cancelled
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl<'a> QueueSlot<'a> {
+    fn join(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, Ordering::Relaxed);
+        Self(queued)
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Whether this request makes the upstream call or waits for an identical one
+enum InflightRole {
+    Leader(broadcast::Sender<SharedReply>),
+    Follower(broadcast::Receiver<SharedReply>),
+}
+
+/// Removes the in-flight entry when the leading request finishes or is cancelled
+struct InflightGuard<'a> {
+    state: &'a AppState,
+    key: &'a str,
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.state
+            .inflight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(self.key);
+    }
+}
+
+/// Run `send` unless an identical request is already upstream, in which case wait for and
+/// reuse its reply. Returns the reply and whether it was reused.
+async fn coalesce(
+    state: &AppState,
+    key: &str,
+    send: impl Future<Output = SharedReply>,
+) -> (SharedReply, bool) {
+    let role = {
+        let mut inflight = state.inflight.lock().unwrap_or_else(|e| e.into_inner());
+        match inflight.get(key) {
+            Some(sender) => InflightRole::Follower(sender.subscribe()),
+            None => {
+                let (sender, _) = broadcast::channel(1);
+                inflight.insert(key.to_string(), sender.clone());
+                InflightRole::Leader(sender)
+            }
+        }
+    };
+
+    match role {
+        InflightRole::Follower(mut receiver) => {
+            info!("Coalescing with an identical in-flight request");
+            let reply = receiver.recv().await.unwrap_or_else(|_| {
+                Err(ProxyError::BadGateway(
+                    "The identical in-flight request was cancelled".to_string(),
+                ))
+            });
+            (reply, true)
+        }
+        InflightRole::Leader(sender) => {
+            let guard = InflightGuard { state, key };
+            let reply = send.await;
+            // Unregister before broadcasting so later arrivals make their own call
+            drop(guard);
+            let _ = sender.send(reply.clone());
+            (reply, false)
+        }
+    }
+}
+
+/// Next gateway in the rotation, or 503 when the gateway list is empty
+fn next_gateway(config: &Config) -> Result<usize, ProxyError> {
+    config
+        .next_gateway_index()
+        .ok_or_else(|| ProxyError::ServiceUnavailable("no gateways available".to_string()))
+}
+
+/// Resolve an `X-Snake-Gateway` request header to a gateway index.
+/// The header is only honored from local clients, the same rule as the admin endpoints.
+fn pinned_gateway(
+    config: &Config,
+    headers: &HeaderMap,
+    extensions: &Extensions,
+) -> Result<Option<usize>, ProxyError> {
+    let Some(value) = headers.get(GATEWAY_HEADER) else {
+        return Ok(None);
+    };
+    if !admin::is_local(extensions) {
+        warn!(
+            "Ignoring {} header from non-local address {}",
+            GATEWAY_HEADER,
+            admin::peer_label(extensions)
+        );
+        return Ok(None);
+    }
+    let reference = value.to_str().unwrap_or_default().trim();
+    config.find_gateway(reference).map(Some).ok_or_else(|| {
+        ProxyError::BadRequest(format!(
+            "Unknown gateway in {} header: '{}' (use a gateway_id or index)",
+            GATEWAY_HEADER, reference
+        ))
+    })
+}
+
+/// Gateway for the session named by the `[stickiness]` header, if the request carries one.
+/// The session id is hashed, so a session keeps its gateway across restarts and instances
+/// while the gateway list is unchanged.
+fn sticky_gateway(config: &Config, headers: &HeaderMap) -> Option<usize> {
+    let session = headers
+        .get(config.sticky_header.as_ref()?)?
+        .as_bytes()
+        .trim_ascii();
+    if session.is_empty() || config.gateways.is_empty() {
+        return None;
+    }
+    let digest = Sha256::digest(session);
+    let hash = u64::from_be_bytes(digest[..8].try_into().ok()?);
+    Some((hash % config.gateways.len() as u64) as usize)
+}
+
+/// Map a failed upstream send to a 502
+fn forward_error(e: reqwest::Error) -> ProxyError {
+    error!("Failed to forward request to Cloudflare: {}", e);
+    ProxyError::BadGateway(format!("Failed to forward request to target: {}", e))
+}
+
+/// Build the cf-aig-authorization header value for a gateway token
+fn gateway_auth_value(token: &str) -> Result<HeaderValue, ProxyError> {
+    format!("Bearer {}", token)
+        .parse()
+        .map_err(|e| ProxyError::BadRequest(format!("Invalid gateway token format: {}", e)))
+}
+
+/// Send the primary request and, if it hasn't answered within `delay`, race it against
+/// the same request on the next gateway. Whichever responds first wins and the other
+/// future is dropped, which aborts its upstream connection.
+/// Returns the index of the gateway whose result is used, alongside that result.
+async fn send_hedged(
+    state: &AppState,
+    primary_index: usize,
+    primary: reqwest::RequestBuilder,
+    upstream: &UpstreamRequest,
+    delay: Duration,
+) -> Result<(usize, Result<reqwest::Response, reqwest::Error>), ProxyError> {
+    let primary = primary.send();
+    tokio::pin!(primary);
+
+    tokio::select! {
+        result = &mut primary => return Ok((primary_index, result)),
+        _ = tokio::time::sleep(delay) => {}
+    }
+
+    let hedge_index = next_gateway(&state.config)?;
+    let hedge_gateway = &state.config.gateways[hedge_index];
+    info!(
+        "Primary gateway has not responded after {} ms, hedging to gateway {}",
+        delay.as_millis(),
+        hedge_gateway.gateway_id
+    );
+    let hedge = upstream.build(state, hedge_gateway)?.send();
+    tokio::pin!(hedge);
+    state.config.record_gateway_request(hedge_index, upstream.body.len());
+
+    // A fast failure shouldn't win the race, so fall back to the other attempt on error
+    let outcome = tokio::select! {
+        result = &mut primary => match result {
+            Ok(response) => {
+                info!("Hedged request: primary gateway responded first");
+                (primary_index, Ok(response))
+            }
+            Err(e) => {
+                info!("Hedged request: primary gateway failed ({}), waiting for hedge", e);
+                state.config.record_gateway_result(primary_index, false);
+                (hedge_index, hedge.await)
+            }
+        },
+        result = &mut hedge => match result {
+            Ok(response) => {
+                info!("Hedged request: hedge gateway {} responded first", hedge_gateway.gateway_id);
+                (hedge_index, Ok(response))
+            }
+            Err(e) => {
+                info!("Hedged request: hedge gateway failed ({}), waiting for primary", e);
+                state.config.record_gateway_result(hedge_index, false);
+                (primary_index, primary.await)
+            }
+        },
+    };
+    Ok(outcome)
+}
+
+/// Send a copy of the request to the shadow gateway in the background, logging only its status and latency
+fn spawn_shadow_request(gateway_id: String, request: reqwest::RequestBuilder) {
+    tokio::spawn(
+        async move {
+            let started = Instant::now();
+            let result = request.send().await;
+            let elapsed_ms = started.elapsed().as_millis();
+            match result {
+                Ok(response) => info!(
+                    "Shadow request to gateway {} finished: status {} in {} ms",
+                    gateway_id,
+                    response.status(),
+                    elapsed_ms
+                ),
+                Err(e) => info!(
+                    "Shadow request to gateway {} failed after {} ms: {}",
+                    gateway_id, elapsed_ms, e
+                ),
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Canned chat completion for `[dev] echo` that answers with the last user message
+fn echo_completion(request: &Value) -> Value {
+    let text = request
+        .get("messages")
+        .and_then(|m| m.as_array())
+        .and_then(|messages| messages.iter().rev().find(|m| m["role"] == "user"))
+        .map(|message| match &message["content"] {
+            Value::String(text) => text.clone(),
+            // Multimodal content: echo the text parts only
+            Value::Array(parts) => parts
+                .iter()
+                .filter_map(|part| part.get("text").and_then(|t| t.as_str()))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => String::new(),
+        })
+        .unwrap_or_default();
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    json!({
+        "id": format!("chatcmpl-echo-{}", Uuid::new_v4().simple()),
+        "object": "chat.completion",
+        "created": created,
+        "model": request.get("model").cloned().unwrap_or(json!("echo")),
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": text},
+            "finish_reason": "stop"
+        }],
+        "usage": {"prompt_tokens": 0, "completion_tokens": 0, "total_tokens": 0}
+    })
+}
+
+/// Wrap an upstream error body as `{"error":{"message","type","code"}}`, or None if it already has that shape
+fn normalize_error_body(status: StatusCode, body: &[u8]) -> Option<bytes::Bytes> {
+    let message = match serde_json::from_slice::<Value>(body) {
+        Ok(json) => {
+            if json
+                .get("error")
+                .and_then(|e| e.get("message"))
+                .is_some()
+            {
+                return None;
+            }
+            // Cloudflare and providers use a few different error shapes
+            json.get("error")
+                .and_then(|e| e.as_str())
+                .or_else(|| {
+                    json.get("errors")
+                        .and_then(|e| e.get(0))
+                        .and_then(|e| e.get("message"))
+                        .and_then(|m| m.as_str())
+                })
+                .or_else(|| json.get(0).and_then(|e| e.get("message")).and_then(|m| m.as_str()))
+                .or_else(|| json.get("message").and_then(|m| m.as_str()))
+                .map(str::to_string)
+                .unwrap_or_else(|| json.to_string())
+        }
+        Err(_) => {
+            let text = String::from_utf8_lossy(body).trim().to_string();
+            if text.is_empty() {
+                status.canonical_reason().unwrap_or("Upstream error").to_string()
+            } else {
+                text
+            }
+        }
+    };
+
+    let envelope = json!({
+        "error": {
+            "message": message,
+            "type": "upstream_error",
+            "code": status.as_u16()
+        }
+    });
+    serde_json::to_vec(&envelope).ok().map(bytes::Bytes::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::Router;
+    use axum::extract::Request;
+
+    /// Header the test upstream answers with, carrying the path and query it was asked for
+    const ECHO_URI_HEADER: &str = "x-echo-uri";
+
+    /// Upstream stand-in that answers with the exact body and content type it received
+    async fn spawn_upstream() -> String {
+        let app = Router::new().fallback(|request: Request| async move {
+            let uri = request.uri().to_string();
+            let content_type = request.headers().get(header::CONTENT_TYPE).cloned();
+            let body = request.into_body().collect().await.unwrap().to_bytes();
+            let mut response = Response::new(Body::from(body));
+            response
+                .headers_mut()
+                .insert(ECHO_URI_HEADER, HeaderValue::from_str(&uri).unwrap());
+            if let Some(content_type) = content_type {
+                response.headers_mut().insert(header::CONTENT_TYPE, content_type);
+            }
+            response
+        });
+        serve(app).await
+    }
+
+    /// Proxy with the given top-level settings and one gateway pointing at `upstream`
+    async fn spawn_proxy(settings: &str, upstream: &str) -> String {
+        let toml = format!(
+            "{}\n[[gateways]]\naccount_id = \"a\"\ngateway_id = \"g\"\ntoken = \"t\"\nbase_url = \"{}\"\n",
+            settings, upstream
+        );
+        let config = Config::from_toml(&toml, "test.toml", None).unwrap();
+        let client = upstream_client_builder(&config).unwrap().build().unwrap();
+        let passthrough = passthrough_client(&config).unwrap();
+        serve(crate::build_router(AppState::new(client, passthrough, config))).await
+    }
+
+    /// Serve `app` on a random local port, returning its base URL
+    async fn serve(app: Router) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn body_over_max_body_bytes_gets_413() {
+        let upstream = spawn_upstream().await;
+        let proxy = spawn_proxy("max_body_bytes = 16", &upstream).await;
+        let url = format!("{}/v1/chat/completions", proxy);
+        let client = Client::new();
+
+        let at_limit = client.post(&url).body(vec![b'x'; 16]).send().await.unwrap();
+        assert_eq!(at_limit.status(), StatusCode::OK);
+
+        let over_limit = client.post(&url).body(vec![b'x'; 17]).send().await.unwrap();
+        assert_eq!(over_limit.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    #[tokio::test]
+    async fn query_string_reaches_upstream() {
+        let upstream = spawn_upstream().await;
+        let proxy = spawn_proxy("", &upstream).await;
+
+        let response = Client::new()
+            .get(format!("{}/v1/models?foo=bar", proxy))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let uri = response.headers()[ECHO_URI_HEADER].to_str().unwrap();
+        assert!(uri.ends_with("/models?foo=bar"), "upstream saw {}", uri);
+    }
+    #[tokio::test]
+    async fn multipart_body_is_forwarded_byte_for_byte() {
+        let upstream = spawn_upstream().await;
+        let proxy = spawn_proxy("", &upstream).await;
+        let content_type = "multipart/form-data; boundary=snake-boundary";
+        // A JSON-looking part and non-UTF-8 bytes, neither of which may be rewritten
+        let mut body = b"--snake-boundary\r\n\
+            Content-Disposition: form-data; name=\"metadata\"\r\n\r\n\
+            {\"model\": \"whisper-1\", \"stream\": true}\r\n\
+            --snake-boundary\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.wav\"\r\n\
+            Content-Type: audio/wav\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(&[0x00, 0xff, 0xfe, 0x80, b'{', 0x7f]);
+        body.extend_from_slice(b"\r\n--snake-boundary--\r\n");
+
+        let response = Client::new()
+            .post(format!("{}/v1/audio/transcriptions", proxy))
+            .header(header::CONTENT_TYPE, content_type)
+            .body(body.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], content_type);
+        assert_eq!(response.bytes().await.unwrap(), body);
+    }
+    #[tokio::test]
+    async fn compressed_response_passes_through_undecoded() {
+        let compressed = gzip(br#"{"object":"list","data":[]}"#).unwrap();
+        let body = compressed.clone();
+        let app = Router::new().fallback(move || async move {
+            (
+                [
+                    (header::CONTENT_TYPE, "application/json"),
+                    (header::CONTENT_ENCODING, "gzip"),
+                ],
+                body,
+            )
+        });
+        let upstream = serve(app).await;
+        let proxy = spawn_proxy("", &upstream).await;
+        let client = Client::builder()
+            .no_gzip()
+            .no_brotli()
+            .no_deflate()
+            .build()
+            .unwrap();
+
+        let response = client
+            .get(format!("{}/v1/models", proxy))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.bytes().await.unwrap(), compressed);
+    }
+
+    #[test]
+    fn only_provider_auth_failures_blame_the_key() {
+        let openai = br#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","code":"invalid_api_key"}}"#;
+        let anthropic = br#"{"type":"error","error":{"type":"authentication_error","message":"invalid x-api-key"}}"#;
+        let gateway = br#"{"success":false,"result":[],"messages":[],"error":[{"code":2009,"message":"Unauthorized"}]}"#;
+        assert!(provider_rejected_key(openai));
+        assert!(provider_rejected_key(anthropic));
+        assert!(!provider_rejected_key(gateway));
+        assert!(!provider_rejected_key(b"<html>401 Unauthorized</html>"));
+    }
+
+    #[tokio::test]
+    async fn bare_root_goes_to_chat_completions() {
+        let upstream = spawn_upstream().await;
+        let proxy = spawn_proxy("", &upstream).await;
+
+        let response = Client::new()
+            .post(format!("{}/", proxy))
+            .body("{}")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ECHO_URI_HEADER], "/compat/chat/completions");
+    }
+
+    #[tokio::test]
+    async fn strict_json_covers_every_chat_completions_alias() {
+        let upstream = spawn_upstream().await;
+        let proxy = spawn_proxy("strict_json = true", &upstream).await;
+        let client = Client::new();
+
+        for path in ["/v1/chat/completions", "/chat/completions", "/"] {
+            let response = client
+                .post(format!("{}{}", proxy, path))
+                .body("not json")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", path);
+        }
+        let embeddings = client
+            .post(format!("{}/v1/embeddings", proxy))
+            .body("not json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(embeddings.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn max_tokens_limit_is_set_on_every_chat_completions_alias() {
+        let upstream = spawn_upstream().await;
+        let proxy = spawn_proxy("max_tokens_limit = 64", &upstream).await;
+        let client = Client::new();
+
+        for path in ["/v1/chat/completions", "/chat/completions", "/"] {
+            let response = client
+                .post(format!("{}{}", proxy, path))
+                .body(r#"{"model":"openai/gpt-4o-mini","messages":[]}"#)
+                .send()
+                .await
+                .unwrap();
+            let forwarded: Value = response.json().await.unwrap();
+            assert_eq!(forwarded["max_tokens"], 64, "{}", path);
+        }
+        let response = client
+            .post(format!("{}/v1/embeddings", proxy))
+            .body(r#"{"model":"openai/text-embedding-3-small","input":"hi"}"#)
+            .send()
+            .await
+            .unwrap();
+        let forwarded: Value = response.json().await.unwrap();
+        assert!(forwarded.get("max_tokens").is_none());
+    }
+
+    #[tokio::test]
+    async fn fallback_skips_models_refused_by_policy() {
+        // Fails the primary model and echoes any other request
+        let app = Router::new().fallback(|body: bytes::Bytes| async move {
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            let status = if request["model"] == "openai/primary" {
+                StatusCode::INTERNAL_SERVER_ERROR
+            } else {
+                StatusCode::OK
+            };
+            (status, body)
+        });
+        let upstream = serve(app).await;
+        let settings = "[policy]\ndenied_models = [\"groq/*\"]\n\
+            [fallback]\n\"openai/primary\" = [\"groq/refused\", \"openai/backup\"]\n";
+        let proxy = spawn_proxy(settings, &upstream).await;
+
+        let response = Client::new()
+            .post(format!("{}/v1/chat/completions", proxy))
+            .body(r#"{"model":"openai/primary","messages":[]}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[FALLBACK_HEADER], "openai/backup");
+        let forwarded: Value = response.json().await.unwrap();
+        assert_eq!(forwarded["model"], "openai/backup");
+    }
+}
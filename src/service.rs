@@ -1,155 +1,527 @@
+use crate::config::{Config, ConfigOverrides, STDIN_CONFIG};
+use crate::error::SnakeError;
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use tracing::info;
 
-const SERVICE_NAME: &str = "snake.service";
-const SERVICE_PATH: &str = "/etc/systemd/system/snake.service";
+/// Service name used unless `--name` is given
+pub const DEFAULT_SERVICE_NAME: &str = "snake";
+const DEFAULT_DESCRIPTION: &str = "Snake - the API proxy";
+const SYSTEM_SERVICE_DIR: &str = "/etc/systemd/system";
+const USER_SERVICE_DIR: &str = ".config/systemd/user";
+const LAUNCHD_LABEL_PREFIX: &str = "io.github.v1cc0";
 
-/// Install and start the systemd service
-pub fn install_service() -> Result<(), Box<dyn std::error::Error>> {
-    info!("Installing snake as systemd service...");
+/// Whether the service commands should use launchd instead of systemd
+fn is_macos() -> bool {
+    env::consts::OS == "macos"
+}
 
-    // Check if running with sudo
-    if env::var("USER").unwrap_or_default() != "root" && env::var("SUDO_USER").is_err() {
+/// Whether the current process runs as root (directly or via sudo)
+fn running_as_root() -> bool {
+    env::var("USER").unwrap_or_default() == "root" || env::var("SUDO_USER").is_ok()
+}
+
+/// Options for installing the service
+#[derive(Debug, Clone, Default)]
+pub struct InstallOptions {
+    /// Account the system unit runs as (defaults depend on the listen port)
+    pub user: Option<String>,
+    /// Install a systemd user unit in ~/.config/systemd/user (no sudo needed)
+    pub user_scope: bool,
+    /// Config instance the service runs with (`--instance`)
+    pub instance: Option<String>,
+    /// Pass `--allow-duplicate-gateways` to the service
+    pub allow_duplicate_gateways: bool,
+    /// Unit `Description=` (launchd has no equivalent), defaults to "Snake - the API proxy"
+    pub description: Option<String>,
+    /// Environment file for the unit; defaults to a `.env` in the working directory if present
+    pub env_file: Option<String>,
+    /// Seconds systemd waits before restarting a crashed service, defaults to 5
+    pub restart_sec: Option<u32>,
+    /// systemd `MemoryMax=`, such as `512M`, `2G` or `80%`
+    pub memory_max: Option<String>,
+    /// systemd `TasksMax=`, such as `512` or `50%`
+    pub tasks_max: Option<String>,
+}
+
+/// Seconds between crash restarts unless `--restart-sec` is given
+const DEFAULT_RESTART_SEC: u32 = 5;
+
+/// Check a `MemoryMax=` value: bytes with an optional K/M/G/T suffix, a percentage or `infinity`
+pub fn parse_memory_max(value: &str) -> Result<String, String> {
+    let digits = value
+        .strip_suffix(['K', 'M', 'G', 'T', '%'])
+        .unwrap_or(value);
+    if value == "infinity" || (!digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())) {
+        Ok(value.to_string())
+    } else {
+        Err(format!(
+            "invalid memory limit '{}', expected e.g. 512M, 2G, 80% or infinity",
+            value
+        ))
+    }
+}
+
+/// Check a `TasksMax=` value: a count, a percentage or `infinity`
+pub fn parse_tasks_max(value: &str) -> Result<String, String> {
+    let digits = value.strip_suffix('%').unwrap_or(value);
+    if value == "infinity" || (!digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())) {
+        Ok(value.to_string())
+    } else {
+        Err(format!(
+            "invalid tasks limit '{}', expected e.g. 512, 50% or infinity",
+            value
+        ))
+    }
+}
+
+/// Service name without a `.service` suffix, checked so it is usable as a unit and file name
+fn base_name(name: &str) -> Result<&str, SnakeError> {
+    let name = name.strip_suffix(".service").unwrap_or(name);
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'));
+    if valid {
+        Ok(name)
+    } else {
+        Err(SnakeError::Service(format!(
+            "Invalid service name '{}': use letters, digits, '-', '_', '.' or '@'",
+            name
+        )))
+    }
+}
+
+/// systemd unit for a service name, e.g. `snake` -> `snake.service`
+pub fn unit_name(name: &str) -> Result<String, SnakeError> {
+    Ok(format!("{}.service", base_name(name)?))
+}
+
+/// `--name <NAME>` to repeat in printed hints, empty for the default service
+fn name_arg(name: &str) -> String {
+    match base_name(name) {
+        Ok(base) if base != DEFAULT_SERVICE_NAME => format!(" --name {}", base),
+        _ => String::new(),
+    }
+}
+
+/// Quote one `ExecStart=` argument per systemd.service(5): double quotes with C-style escapes,
+/// plus `%%` and `$$` so specifiers and variables aren't expanded
+fn systemd_exec_arg(arg: &str) -> String {
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    for c in arg.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '%' => quoted.push_str("%%"),
+            '$' => quoted.push_str("$$"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Escape a path for settings like `WorkingDirectory=` and `EnvironmentFile=`, which take the
+/// rest of the line literally (quotes would become part of the path), so spaces need no
+/// quoting; only `%` specifiers are doubled. Line breaks cannot be represented.
+fn systemd_path_value(path: &str) -> Result<String, SnakeError> {
+    if path.contains(['\n', '\r']) {
+        return Err(SnakeError::Service(format!(
+            "Path {:?} contains a line break and cannot be written to a systemd unit",
+            path
+        )));
+    }
+    Ok(path.replace('%', "%%"))
+}
+
+/// Escape text for a plist `<string>` element
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// `snake service <command>` as it has to be typed for the unit's scope
+fn service_command_hint(command: &str, name: &str, user_scope: bool) -> String {
+    if user_scope {
+        format!("snake service {}{} --user-scope", command, name_arg(name))
+    } else {
+        format!("sudo snake service {}{}", command, name_arg(name))
+    }
+}
+
+/// `systemctl` as it has to be typed for the unit's scope
+fn systemctl_hint(user_scope: bool) -> &'static str {
+    if user_scope { "systemctl --user" } else { "sudo systemctl" }
+}
+
+/// Path of the systemd unit file for the requested scope
+fn systemd_unit_path(user_scope: bool, unit: &str) -> Result<PathBuf, SnakeError> {
+    if !user_scope {
+        return Ok(Path::new(SYSTEM_SERVICE_DIR).join(unit));
+    }
+    let home =
+        env::var("HOME").map_err(|_| SnakeError::Service("HOME is not set".to_string()))?;
+    Ok(PathBuf::from(home).join(USER_SERVICE_DIR).join(unit))
+}
+
+/// Build a systemctl command targeting the system or user manager
+fn systemctl(user_scope: bool) -> Command {
+    let mut command = Command::new("systemctl");
+    if user_scope {
+        command.arg("--user");
+    }
+    command
+}
+
+/// Port the proxy will listen on according to the config, if it can be loaded
+fn configured_port(
+    config_path: &str,
+    instance: Option<&str>,
+    allow_duplicate_gateways: bool,
+) -> Option<u16> {
+    let overrides = ConfigOverrides {
+        instance: instance.map(str::to_string),
+        allow_duplicate_gateways,
+        ..Default::default()
+    };
+    let config = Config::load(config_path, &overrides).ok()?;
+    Some(if config.https_server {
+        config.https_port
+    } else {
+        config.http_port
+    })
+}
+
+/// Pick the account for a system unit: explicit user, else the invoking user for unprivileged ports
+fn service_user(explicit: Option<String>, port: Option<u16>) -> (String, String) {
+    if let Some(user) = explicit {
+        return (user, "set via --user".to_string());
+    }
+    match port {
+        Some(port) if port >= 1024 => {
+            let invoking_user = env::var("SUDO_USER")
+                .or_else(|_| env::var("USER"))
+                .unwrap_or_else(|_| "root".to_string());
+            (invoking_user, format!("port {} does not require root", port))
+        }
+        Some(port) => ("root".to_string(), format!("required for privileged port {}", port)),
+        None => ("root".to_string(), "config port unknown, assuming privileged".to_string()),
+    }
+}
+
+/// Install and start the service (systemd on Linux, launchd on macOS, the SCM on Windows)
+#[cfg_attr(windows, allow(unreachable_code))]
+pub fn install_service(
+    config_path: &str,
+    name: &str,
+    options: InstallOptions,
+) -> Result<(), SnakeError> {
+    if config_path == STDIN_CONFIG {
+        return Err(SnakeError::Service(
+            "The service needs a config file; pass its path with --config instead of -".to_string(),
+        ));
+    }
+    // The service may start from any directory, so pin the absolute config path
+    let config_path = Path::new(config_path);
+    if !config_path.exists() {
+        eprintln!("❌ Error: Config file not found: {}", config_path.display());
+        eprintln!("Pass the config the service should use with: snake --config <path> service start");
+        return Err(SnakeError::Service(format!(
+            "Config file not found: {}",
+            config_path.display()
+        )));
+    }
+    let config_path = fs::canonicalize(config_path)?;
+    let config_path_str = config_path
+        .to_str()
+        .ok_or_else(|| SnakeError::Service("Failed to get config path".to_string()))?;
+
+    #[cfg(windows)]
+    return crate::winservice::install_service(config_path_str, base_name(name)?, options);
+
+    if is_macos() {
+        if options.env_file.is_some()
+            || options.restart_sec.is_some()
+            || options.memory_max.is_some()
+            || options.tasks_max.is_some()
+        {
+            eprintln!(
+                "⚠️  --env-file, --restart-sec, --memory-max and --tasks-max are only supported \
+                 for systemd units, ignoring them"
+            );
+        }
+        return install_launchd_service(config_path_str, &options, name);
+    }
+
+    let InstallOptions {
+        user,
+        user_scope,
+        instance,
+        allow_duplicate_gateways,
+        description,
+        env_file,
+        restart_sec,
+        memory_max,
+        tasks_max,
+    } = options;
+    let restart_sec = restart_sec.unwrap_or(DEFAULT_RESTART_SEC);
+    let mut limit_lines = String::new();
+    if let Some(memory_max) = &memory_max {
+        limit_lines.push_str(&format!("MemoryMax={}\n", memory_max));
+    }
+    if let Some(tasks_max) = &tasks_max {
+        limit_lines.push_str(&format!("TasksMax={}\n", tasks_max));
+    }
+    let unit = unit_name(name)?;
+    let name_arg = name_arg(name);
+    let description = description.unwrap_or_else(|| DEFAULT_DESCRIPTION.to_string());
+    if description.contains(['\n', '\r']) {
+        return Err(SnakeError::Service(
+            "The service description must be a single line".to_string(),
+        ));
+    }
+    let mut instance_arg = instance
+        .as_ref()
+        .map(|name| format!(" --instance {}", systemd_exec_arg(name)))
+        .unwrap_or_default();
+    if allow_duplicate_gateways {
+        instance_arg.push_str(" --allow-duplicate-gateways");
+    }
+    let unit_path = systemd_unit_path(user_scope, &unit)?;
+
+    info!("Installing snake as systemd service {}...", unit);
+
+    // Check if running with sudo (user units live in the home directory)
+    if !user_scope && !running_as_root() {
         eprintln!("❌ Error: This command requires sudo privileges");
-        eprintln!("Please run: sudo snake service start");
-        return Err("Requires sudo".into());
+        eprintln!("Please run: sudo snake service start{}", name_arg);
+        return Err(SnakeError::Service("Requires sudo".to_string()));
     }
 
     // Get the current binary path
     let binary_path = env::current_exe()?;
     let binary_path_str = binary_path
         .to_str()
-        .ok_or("Failed to get binary path")?;
+        .ok_or_else(|| SnakeError::Service("Failed to get binary path".to_string()))?;
 
     // Get the working directory (where .env is located)
     let working_dir = env::current_dir()?;
     let working_dir_str = working_dir
         .to_str()
-        .ok_or("Failed to get working directory")?;
+        .ok_or_else(|| SnakeError::Service("Failed to get working directory".to_string()))?;
+
+    // Systemd starts the unit elsewhere, so dotenvy alone wouldn't find the .env
+    let env_file = match env_file {
+        Some(path) => {
+            let path = fs::canonicalize(&path).map_err(|e| {
+                SnakeError::Service(format!("Cannot use env file {}: {}", path, e))
+            })?;
+            // An explicit file must exist when the unit starts, so no '-' prefix
+            Some((path, false))
+        }
+        None => {
+            let path = working_dir.join(".env");
+            path.is_file().then_some((path, true))
+        }
+    };
+    let env_file_line = env_file
+        .as_ref()
+        .map(|(path, optional)| -> Result<String, SnakeError> {
+            Ok(format!(
+                "EnvironmentFile={}{}\n",
+                if *optional { "-" } else { "" },
+                systemd_path_value(&path.display().to_string())?
+            ))
+        })
+        .transpose()?
+        .unwrap_or_default();
 
     println!("📋 Service Configuration:");
+
+    // User units always run as the owning user, system units need an explicit account
+    let port = configured_port(config_path_str, instance.as_deref(), allow_duplicate_gateways);
+    let user_line = if user_scope {
+        println!("  ├─ Scope: user (systemctl --user)");
+        if matches!(port, Some(port) if port < 1024) {
+            println!("  │   ⚠️  User units cannot bind privileged ports (< 1024)");
+        }
+        String::new()
+    } else {
+        let (service_user, reason) = service_user(user, port);
+        println!("  ├─ User: {} ({})", service_user, reason);
+        format!("User={}\n", service_user)
+    };
     println!("  ├─ Binary: {}", binary_path_str);
+    println!("  ├─ Config: {}", config_path_str);
+    if let Some(name) = &instance {
+        println!("  ├─ Instance: {}", name);
+    }
     println!("  ├─ Working Directory: {}", working_dir_str);
-    println!("  ├─ User: root (required for HTTPS port 443)");
-    println!("  └─ Service File: {}", SERVICE_PATH);
+    match &env_file {
+        Some((path, true)) => println!("  ├─ Environment File: {} (detected)", path.display()),
+        Some((path, false)) => println!("  ├─ Environment File: {}", path.display()),
+        None => println!("  ├─ Environment File: none (no .env in the working directory)"),
+    }
+    println!("  ├─ Description: {}", description);
+    println!("  ├─ Restart: always, after {}s", restart_sec);
+    if memory_max.is_some() || tasks_max.is_some() {
+        println!(
+            "  ├─ Limits: MemoryMax={}, TasksMax={}",
+            memory_max.as_deref().unwrap_or("unset"),
+            tasks_max.as_deref().unwrap_or("unset")
+        );
+    }
+    println!("  └─ Service File: {}", unit_path.display());
 
     // Create systemd service file content
-    // Note: User=root is required to bind to privileged ports (< 1024) like HTTPS 443
+    // Note: privileged ports (< 1024) like HTTPS 443 still require User=root
+    let wanted_by = if user_scope { "default.target" } else { "multi-user.target" };
     let service_content = format!(
         r#"[Unit]
-Description=Snake - the API proxy (HTTPS)
+Description={}
 After=network.target
 
 [Service]
 Type=simple
-User=root
-WorkingDirectory={}
-ExecStart={} serve
+{}WorkingDirectory={}
+{}ExecStart={} --config {}{} serve
+ExecReload=/bin/kill -HUP $MAINPID
 Restart=always
-RestartSec=5
-StandardOutput=journal
+RestartSec={}
+{}StandardOutput=journal
 StandardError=journal
 
 [Install]
-WantedBy=multi-user.target
+WantedBy={}
 "#,
-        working_dir_str, binary_path_str
+        description.replace('%', "%%"),
+        user_line,
+        systemd_path_value(working_dir_str)?,
+        env_file_line,
+        systemd_exec_arg(binary_path_str),
+        systemd_exec_arg(config_path_str),
+        instance_arg,
+        restart_sec,
+        limit_lines,
+        wanted_by
     );
 
     // Write service file
     println!("\n📝 Creating systemd service file...");
-    fs::write(SERVICE_PATH, service_content)?;
-    println!("✓ Service file created: {}", SERVICE_PATH);
+    if let Some(parent) = unit_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&unit_path, service_content)?;
+    println!("✓ Service file created: {}", unit_path.display());
 
     // Reload systemd daemon
     println!("\n🔄 Reloading systemd daemon...");
-    let reload_output = Command::new("systemctl")
+    let reload_output = systemctl(user_scope)
         .arg("daemon-reload")
         .output()?;
 
     if !reload_output.status.success() {
         let error = String::from_utf8_lossy(&reload_output.stderr);
         eprintln!("❌ Failed to reload systemd daemon: {}", error);
-        return Err("systemctl daemon-reload failed".into());
+        return Err(SnakeError::Service("systemctl daemon-reload failed".to_string()));
     }
     println!("✓ Systemd daemon reloaded");
 
     // Enable the service
     println!("\n🔧 Enabling service (start on boot)...");
-    let enable_output = Command::new("systemctl")
+    let enable_output = systemctl(user_scope)
         .arg("enable")
-        .arg(SERVICE_NAME)
+        .arg(&unit)
         .output()?;
 
     if !enable_output.status.success() {
         let error = String::from_utf8_lossy(&enable_output.stderr);
         eprintln!("❌ Failed to enable service: {}", error);
-        return Err("systemctl enable failed".into());
+        return Err(SnakeError::Service("systemctl enable failed".to_string()));
     }
     println!("✓ Service enabled");
 
     // Start the service
     println!("\n🚀 Starting service...");
-    let start_output = Command::new("systemctl")
+    let start_output = systemctl(user_scope)
         .arg("start")
-        .arg(SERVICE_NAME)
+        .arg(&unit)
         .output()?;
 
     if !start_output.status.success() {
         let error = String::from_utf8_lossy(&start_output.stderr);
         eprintln!("❌ Failed to start service: {}", error);
-        return Err("systemctl start failed".into());
+        return Err(SnakeError::Service("systemctl start failed".to_string()));
     }
     println!("✓ Service started");
 
     // Check service status
     println!("\n📊 Service Status:");
-    let status_output = Command::new("systemctl")
+    let status_output = systemctl(user_scope)
         .arg("status")
-        .arg(SERVICE_NAME)
+        .arg(&unit)
         .arg("--no-pager")
         .output()?;
 
     let status = String::from_utf8_lossy(&status_output.stdout);
     println!("{}", status);
 
-    println!("\n✅ Snake service installed and started successfully!");
+    println!("\n✅ Snake service {} installed and started successfully!", unit);
+    let (sudo, scope) = if user_scope { ("", " --user-scope") } else { ("sudo ", "") };
     println!("\nUseful commands:");
-    println!("  sudo systemctl status snake    - Check service status");
-    println!("  sudo systemctl restart snake   - Restart service");
-    println!("  sudo journalctl -u snake -f    - View logs");
-    println!("  sudo snake service stop        - Stop and disable service");
+    println!("  snake service status{}{}    - Check service status", name_arg, scope);
+    println!("  {}snake service restart{}{}   - Restart service", sudo, name_arg, scope);
+    println!("  {}snake service logs{}{}      - View logs", sudo, name_arg, scope);
+    println!("  {}snake service stop{}{}      - Stop and disable service", sudo, name_arg, scope);
+    if user_scope {
+        println!("\nTo keep the service running after logout: loginctl enable-linger $USER");
+    }
 
     Ok(())
 }
 
-/// Stop and uninstall the systemd service
-pub fn uninstall_service() -> Result<(), Box<dyn std::error::Error>> {
-    info!("Uninstalling snake systemd service...");
+/// Stop and uninstall the service (systemd on Linux, launchd on macOS)
+#[cfg_attr(windows, allow(unreachable_code))]
+pub fn uninstall_service(user_scope: bool, name: &str) -> Result<(), SnakeError> {
+    #[cfg(windows)]
+    return crate::winservice::uninstall_service(base_name(name)?);
+
+    if is_macos() {
+        return uninstall_launchd_service(name);
+    }
+
+    let unit = unit_name(name)?;
+    let unit_path = systemd_unit_path(user_scope, &unit)?;
+
+    info!("Uninstalling snake systemd service {}...", unit);
 
     // Check if running with sudo
-    if env::var("USER").unwrap_or_default() != "root" && env::var("SUDO_USER").is_err() {
+    if !user_scope && !running_as_root() {
         eprintln!("❌ Error: This command requires sudo privileges");
-        eprintln!("Please run: sudo snake service stop");
-        return Err("Requires sudo".into());
+        eprintln!("Please run: sudo snake service stop{}", name_arg(name));
+        return Err(SnakeError::Service("Requires sudo".to_string()));
     }
 
     // Check if service file exists
-    if !Path::new(SERVICE_PATH).exists() {
-        eprintln!("⚠️  Service file not found: {}", SERVICE_PATH);
+    if !unit_path.exists() {
+        eprintln!("⚠️  Service file not found: {}", unit_path.display());
         eprintln!("Service may not be installed or already removed.");
         return Ok(());
     }
 
-    println!("🛑 Stopping snake service...");
+    println!("🛑 Stopping {}...", unit);
 
     // Stop the service
-    let stop_output = Command::new("systemctl")
+    let stop_output = systemctl(user_scope)
         .arg("stop")
-        .arg(SERVICE_NAME)
+        .arg(&unit)
         .output()?;
 
     if !stop_output.status.success() {
@@ -163,9 +535,9 @@ pub fn uninstall_service() -> Result<(), Box<dyn std::error::Error>> {
 
     // Disable the service
     println!("\n🔧 Disabling service...");
-    let disable_output = Command::new("systemctl")
+    let disable_output = systemctl(user_scope)
         .arg("disable")
-        .arg(SERVICE_NAME)
+        .arg(&unit)
         .output()?;
 
     if !disable_output.status.success() {
@@ -178,12 +550,12 @@ pub fn uninstall_service() -> Result<(), Box<dyn std::error::Error>> {
 
     // Remove service file
     println!("\n🗑️  Removing service file...");
-    fs::remove_file(SERVICE_PATH)?;
-    println!("✓ Service file removed: {}", SERVICE_PATH);
+    fs::remove_file(&unit_path)?;
+    println!("✓ Service file removed: {}", unit_path.display());
 
     // Reload systemd daemon
     println!("\n🔄 Reloading systemd daemon...");
-    let reload_output = Command::new("systemctl")
+    let reload_output = systemctl(user_scope)
         .arg("daemon-reload")
         .output()?;
 
@@ -193,6 +565,487 @@ pub fn uninstall_service() -> Result<(), Box<dyn std::error::Error>> {
     }
     println!("✓ Systemd daemon reloaded");
 
+    println!("\n✅ Snake service {} stopped and uninstalled successfully!", unit);
+
+    Ok(())
+}
+
+/// Show the current service status
+#[cfg_attr(windows, allow(unreachable_code))]
+pub fn service_status(user_scope: bool, name: &str) -> Result<(), SnakeError> {
+    #[cfg(windows)]
+    return crate::winservice::service_status(base_name(name)?);
+
+    if is_macos() {
+        let label = launchd_label(name)?;
+        let output = Command::new("launchctl")
+            .arg("list")
+            .arg(&label)
+            .output()?;
+        if !output.status.success() {
+            println!("⚠️  {} is not loaded", label);
+            println!("Install it with: snake service start{}", name_arg(name));
+            return Ok(());
+        }
+        println!("📊 Service Status:");
+        println!("{}", String::from_utf8_lossy(&output.stdout));
+        return Ok(());
+    }
+
+    let unit = unit_name(name)?;
+    let unit_path = systemd_unit_path(user_scope, &unit)?;
+    if !unit_path.exists() {
+        println!("⚠️  Service file not found: {}", unit_path.display());
+        println!(
+            "Install it with: {}",
+            service_command_hint("start", name, user_scope)
+        );
+        return Ok(());
+    }
+
+    // systemctl status exits non-zero for inactive units, which is still a valid report
+    let status_output = systemctl(user_scope)
+        .arg("status")
+        .arg(&unit)
+        .arg("--no-pager")
+        .output()?;
+
+    println!("📊 Service Status:");
+    println!("{}", String::from_utf8_lossy(&status_output.stdout));
+
+    let is_active = systemctl(user_scope)
+        .arg("is-active")
+        .arg(&unit)
+        .output()?
+        .status
+        .success();
+    if is_active {
+        println!("✅ {} is running", unit);
+    } else {
+        println!("⚠️  {} is not running", unit);
+        println!("Start it with: {} start {}", systemctl_hint(user_scope), unit);
+    }
+
+    Ok(())
+}
+
+/// Restart the installed service
+#[cfg_attr(windows, allow(unreachable_code))]
+pub fn restart_service(user_scope: bool, name: &str) -> Result<(), SnakeError> {
+    #[cfg(windows)]
+    return crate::winservice::restart_service(base_name(name)?);
+
+    if is_macos() {
+        let plist_path = launchd_plist_path(name)?;
+        if !plist_path.exists() {
+            eprintln!("❌ Service file not found: {}", plist_path.display());
+            return Err(SnakeError::Service("Service is not installed".to_string()));
+        }
+
+        println!("🔄 Restarting {}...", launchd_label(name)?);
+        if let Err(e) = reload_launchd(&plist_path) {
+            eprintln!("❌ Failed to restart service: {}", e);
+            return Err(e);
+        }
+        println!("✓ Service restarted");
+        return Ok(());
+    }
+
+    if !user_scope && !running_as_root() {
+        eprintln!("❌ Error: This command requires sudo privileges");
+        eprintln!("Please run: sudo snake service restart{}", name_arg(name));
+        return Err(SnakeError::Service("Requires sudo".to_string()));
+    }
+
+    let unit = unit_name(name)?;
+    let unit_path = systemd_unit_path(user_scope, &unit)?;
+    if !unit_path.exists() {
+        eprintln!("❌ Service file not found: {}", unit_path.display());
+        eprintln!(
+            "Install it with: {}",
+            service_command_hint("start", name, user_scope)
+        );
+        return Err(SnakeError::Service("Service is not installed".to_string()));
+    }
+
+    println!("🔄 Restarting {}...", unit);
+    let restart_output = systemctl(user_scope)
+        .arg("restart")
+        .arg(&unit)
+        .output()?;
+
+    if !restart_output.status.success() {
+        let error = String::from_utf8_lossy(&restart_output.stderr);
+        eprintln!("❌ Failed to restart service: {}", error);
+        return Err(SnakeError::Service("systemctl restart failed".to_string()));
+    }
+    println!("✓ Service restarted");
+
+    Ok(())
+}
+
+/// Restart a launchd service by reloading its plist; launchctl has no portable restart
+fn reload_launchd(plist_path: &Path) -> Result<(), SnakeError> {
+    Command::new("launchctl")
+        .arg("unload")
+        .arg(plist_path)
+        .output()?;
+    let load_output = Command::new("launchctl")
+        .arg("load")
+        .arg("-w")
+        .arg(plist_path)
+        .output()?;
+    if !load_output.status.success() {
+        return Err(SnakeError::Service(format!(
+            "launchctl load failed: {}",
+            String::from_utf8_lossy(&load_output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// A service found by `installed_service`, which `snake update` restarts onto the new binary
+pub(crate) enum InstalledService {
+    /// systemd unit in the system or the user manager
+    Systemd {
+        name: String,
+        unit: String,
+        user_scope: bool,
+    },
+    /// launchd daemon (as root) or agent
+    Launchd {
+        name: String,
+        label: String,
+        plist_path: PathBuf,
+    },
+}
+
+/// Find the installed service called `name`: the system unit and then the user unit on Linux,
+/// the plist on macOS. Windows services aren't detected, so `None` there.
+pub(crate) fn installed_service(name: &str) -> Result<Option<InstalledService>, SnakeError> {
+    if cfg!(windows) {
+        return Ok(None);
+    }
+    if is_macos() {
+        let (label, plist_path) = (launchd_label(name)?, launchd_plist_path(name)?);
+        return Ok(plist_path.exists().then(|| InstalledService::Launchd {
+            name: name.to_string(),
+            label,
+            plist_path,
+        }));
+    }
+    let unit = unit_name(name)?;
+    for user_scope in [false, true] {
+        // Without HOME there is no user unit to find
+        if systemd_unit_path(user_scope, &unit).is_ok_and(|path| path.exists()) {
+            return Ok(Some(InstalledService::Systemd {
+                name: name.to_string(),
+                unit,
+                user_scope,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+impl InstalledService {
+    /// The unit or launchd label, for messages
+    pub(crate) fn label(&self) -> &str {
+        match self {
+            InstalledService::Systemd { unit, .. } => unit,
+            InstalledService::Launchd { label, .. } => label,
+        }
+    }
+
+    /// Whether the service is running right now
+    pub(crate) fn is_active(&self) -> Result<bool, SnakeError> {
+        match self {
+            InstalledService::Systemd {
+                unit, user_scope, ..
+            } => Ok(systemctl(*user_scope)
+                .arg("is-active")
+                .arg(unit)
+                .output()?
+                .status
+                .success()),
+            // A loaded job that isn't running has no "PID" entry
+            InstalledService::Launchd { label, .. } => {
+                let output = Command::new("launchctl").arg("list").arg(label).output()?;
+                Ok(output.status.success()
+                    && String::from_utf8_lossy(&output.stdout).contains("\"PID\""))
+            }
+        }
+    }
+
+    /// Restart the service without printing anything
+    pub(crate) fn restart(&self) -> Result<(), SnakeError> {
+        match self {
+            InstalledService::Systemd {
+                unit, user_scope, ..
+            } => {
+                let output = systemctl(*user_scope).arg("restart").arg(unit).output()?;
+                if !output.status.success() {
+                    return Err(SnakeError::Service(format!(
+                        "systemctl restart failed: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    )));
+                }
+                Ok(())
+            }
+            InstalledService::Launchd { plist_path, .. } => reload_launchd(plist_path),
+        }
+    }
+
+    /// `snake service <command>` as it has to be typed for this service
+    pub(crate) fn command_hint(&self, command: &str) -> String {
+        match self {
+            InstalledService::Systemd {
+                name, user_scope, ..
+            } => service_command_hint(command, name, *user_scope),
+            // launchd picks the daemon or the agent by whether it runs as root
+            InstalledService::Launchd { name, .. } if running_as_root() => {
+                format!("sudo snake service {}{}", command, name_arg(name))
+            }
+            InstalledService::Launchd { name, .. } => {
+                format!("snake service {}{}", command, name_arg(name))
+            }
+        }
+    }
+
+    /// Command that shows the service's recent log output
+    pub(crate) fn logs_hint(&self) -> String {
+        match self {
+            InstalledService::Systemd {
+                unit, user_scope, ..
+            } => {
+                let user = if *user_scope { " --user" } else { "" };
+                format!("journalctl{} -u {} -n 50", user, unit)
+            }
+            InstalledService::Launchd { name, .. } => match launchd_log_path(name) {
+                Ok(log_path) => format!("tail -n 50 {}", log_path.display()),
+                Err(_) => format!("snake service logs{}", name_arg(name)),
+            },
+        }
+    }
+}
+
+/// Follow the service logs until interrupted
+#[cfg_attr(windows, allow(unreachable_code))]
+pub fn service_logs(user_scope: bool, name: &str) -> Result<(), SnakeError> {
+    // The SCM doesn't capture output; use access_log or the Event Viewer instead
+    #[cfg(windows)]
+    return Err(SnakeError::Service(format!(
+        "Following logs is not supported for the Windows service {}; set access_log in the config",
+        base_name(name)?
+    )));
+
+    // Inherit stdio so the output streams straight to the terminal
+    let status = if is_macos() {
+        let log_path = launchd_log_path(name)?;
+        println!("📜 Following {} (Ctrl+C to stop)...\n", log_path.display());
+        Command::new("tail").arg("-f").arg(&log_path).status()?
+    } else {
+        let unit = unit_name(name)?;
+        println!("📜 Following {} logs (Ctrl+C to stop)...\n", unit);
+        let mut journalctl = Command::new("journalctl");
+        if user_scope {
+            journalctl.arg("--user");
+        }
+        journalctl
+            .arg("-u")
+            .arg(&unit)
+            .arg("-f")
+            .status()?
+    };
+
+    if !status.success() {
+        return Err(SnakeError::Service(format!("Log viewer exited with {}", status)));
+    }
+
+    Ok(())
+}
+
+/// launchd label for a service name, e.g. `snake` -> `io.github.v1cc0.snake`
+fn launchd_label(name: &str) -> Result<String, SnakeError> {
+    Ok(format!("{}.{}", LAUNCHD_LABEL_PREFIX, base_name(name)?))
+}
+
+/// Location of the launchd plist: a system daemon when root, otherwise a per-user agent
+fn launchd_plist_path(name: &str) -> Result<PathBuf, SnakeError> {
+    let file_name = format!("{}.plist", launchd_label(name)?);
+    if running_as_root() {
+        return Ok(Path::new("/Library/LaunchDaemons").join(file_name));
+    }
+    let home =
+        env::var("HOME").map_err(|_| SnakeError::Service("HOME is not set".to_string()))?;
+    Ok(PathBuf::from(home).join("Library/LaunchAgents").join(file_name))
+}
+
+/// Location of the service log file written by launchd
+pub(crate) fn launchd_log_path(name: &str) -> Result<PathBuf, SnakeError> {
+    let file_name = format!("{}.log", base_name(name)?);
+    if running_as_root() {
+        return Ok(Path::new("/var/log").join(file_name));
+    }
+    let home =
+        env::var("HOME").map_err(|_| SnakeError::Service("HOME is not set".to_string()))?;
+    Ok(PathBuf::from(home).join("Library/Logs").join(file_name))
+}
+
+/// Install and load the launchd service on macOS
+fn install_launchd_service(
+    config_path: &str,
+    options: &InstallOptions,
+    name: &str,
+) -> Result<(), SnakeError> {
+    let instance = options.instance.as_deref();
+    let label = launchd_label(name)?;
+    let name_arg = name_arg(name);
+    info!("Installing snake as launchd service...");
+
+    let binary_path = env::current_exe()?;
+    let binary_path_str = binary_path
+        .to_str()
+        .ok_or_else(|| SnakeError::Service("Failed to get binary path".to_string()))?;
+
+    let working_dir = env::current_dir()?;
+    let working_dir_str = working_dir
+        .to_str()
+        .ok_or_else(|| SnakeError::Service("Failed to get working directory".to_string()))?;
+
+    let plist_path = launchd_plist_path(name)?;
+    let log_path = launchd_log_path(name)?;
+    let log_path_str = log_path
+        .to_str()
+        .ok_or_else(|| SnakeError::Service("Failed to get log path".to_string()))?;
+
+    println!("📋 Service Configuration:");
+    println!("  ├─ Binary: {}", binary_path_str);
+    println!("  ├─ Config: {}", config_path);
+    if let Some(name) = instance {
+        println!("  ├─ Instance: {}", name);
+    }
+    println!("  ├─ Working Directory: {}", working_dir_str);
+    println!(
+        "  ├─ Type: {}",
+        if running_as_root() { "LaunchDaemon (system)" } else { "LaunchAgent (user)" }
+    );
+    println!("  ├─ Log File: {}", log_path_str);
+    println!("  └─ Service File: {}", plist_path.display());
+
+    let mut instance_args = instance
+        .map(|name| {
+            format!(
+                "        <string>--instance</string>\n        <string>{}</string>\n",
+                xml_escape(name)
+            )
+        })
+        .unwrap_or_default();
+    if options.allow_duplicate_gateways {
+        instance_args.push_str("        <string>--allow-duplicate-gateways</string>\n");
+    }
+
+    let plist_content = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{}</string>
+        <string>--config</string>
+        <string>{}</string>
+{}        <string>serve</string>
+    </array>
+    <key>WorkingDirectory</key>
+    <string>{}</string>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>{}</string>
+    <key>StandardErrorPath</key>
+    <string>{}</string>
+</dict>
+</plist>
+"#,
+        xml_escape(&label),
+        xml_escape(binary_path_str),
+        xml_escape(config_path),
+        instance_args,
+        xml_escape(working_dir_str),
+        xml_escape(log_path_str),
+        xml_escape(log_path_str)
+    );
+
+    println!("\n📝 Creating launchd plist...");
+    if let Some(parent) = plist_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if let Some(parent) = log_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&plist_path, plist_content)?;
+    println!("✓ Service file created: {}", plist_path.display());
+
+    println!("\n🚀 Loading service...");
+    let load_output = Command::new("launchctl")
+        .arg("load")
+        .arg("-w")
+        .arg(&plist_path)
+        .output()?;
+
+    if !load_output.status.success() {
+        let error = String::from_utf8_lossy(&load_output.stderr);
+        eprintln!("❌ Failed to load service: {}", error);
+        return Err(SnakeError::Service("launchctl load failed".to_string()));
+    }
+    println!("✓ Service loaded");
+
+    println!("\n✅ Snake service {} installed and started successfully!", label);
+    println!("\nUseful commands:");
+    println!("  snake service status{}    - Check service status", name_arg);
+    println!("  snake service restart{}   - Restart service", name_arg);
+    println!("  snake service logs{}      - View logs ({})", name_arg, log_path_str);
+    println!("  snake service stop{}      - Stop and remove service", name_arg);
+
+    Ok(())
+}
+
+/// Unload and remove the launchd service on macOS
+fn uninstall_launchd_service(name: &str) -> Result<(), SnakeError> {
+    info!("Uninstalling snake launchd service...");
+
+    let plist_path = launchd_plist_path(name)?;
+    if !plist_path.exists() {
+        eprintln!("⚠️  Service file not found: {}", plist_path.display());
+        eprintln!("Service may not be installed or already removed.");
+        return Ok(());
+    }
+
+    println!("🛑 Unloading snake service...");
+    let unload_output = Command::new("launchctl")
+        .arg("unload")
+        .arg("-w")
+        .arg(&plist_path)
+        .output()?;
+
+    if !unload_output.status.success() {
+        let error = String::from_utf8_lossy(&unload_output.stderr);
+        // Don't fail if the service was never loaded
+        if !error.contains("Could not find") {
+            eprintln!("⚠️  Warning: {}", error);
+        }
+    }
+    println!("✓ Service unloaded");
+
+    println!("\n🗑️  Removing service file...");
+    fs::remove_file(&plist_path)?;
+    println!("✓ Service file removed: {}", plist_path.display());
+
     println!("\n✅ Snake service stopped and uninstalled successfully!");
 
     Ok(())
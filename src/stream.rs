@@ -5,121 +5,50 @@ use axum::{
 };
 use serde_json::{Value, json};
 use tokio_stream::wrappers::ReceiverStream;
+use tracing::error;
+
+/// Pause between simulated stream chunks
+pub const CHUNK_DELAY: std::time::Duration = std::time::Duration::from_millis(30);
+
+/// Longest raw upstream body logged when conversion fails, in characters
+const RAW_BODY_PREVIEW_CHARS: usize = 200;
 
 /// Converts a complete response to SSE (Server-Sent Events) stream format
 pub fn convert_to_sse_stream(status: StatusCode, response_bytes: bytes::Bytes) -> Response {
+    let Some(json_response) = parse_response_json(&response_bytes) else {
+        error!(
+            "Upstream response is not valid JSON, sending SSE error event. Raw body: {}",
+            truncate_chars(&String::from_utf8_lossy(&response_bytes), RAW_BODY_PREVIEW_CHARS)
+        );
+        return sse_error_response(status, "Upstream returned a response that is not valid JSON");
+    };
+    if json_response
+        .get("choices")
+        .and_then(|v| v.as_array())
+        .is_some_and(|choices| choices.is_empty())
+    {
+        error!(
+            "Upstream response has no choices, sending SSE error event. Raw body: {}",
+            truncate_chars(&String::from_utf8_lossy(&response_bytes), RAW_BODY_PREVIEW_CHARS)
+        );
+        return sse_error_response(status, "Upstream returned no choices");
+    }
+
     let (tx, rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(100);
 
     tokio::spawn(async move {
-        // Parse the response JSON
-        if let Ok(json_response) = serde_json::from_slice::<Value>(&response_bytes) {
-            // Check if it's a chat completion response
-            if let Some(choices) = json_response.get("choices").and_then(|v| v.as_array()) {
-                if let Some(first_choice) = choices.first() {
-                    // Extract the full content from the message
-                    if let Some(content) = first_choice
-                        .get("message")
-                        .and_then(|m| m.get("content"))
-                        .and_then(|c| c.as_str())
-                    {
-                        // Get metadata
-                        let created = json_response.get("created").cloned().unwrap_or(json!(0));
-                        let id = json_response.get("id").cloned().unwrap_or(json!("unknown"));
-                        let model = json_response
-                            .get("model")
-                            .cloned()
-                            .unwrap_or(json!("unknown"));
-
-                        // Split content into words for streaming simulation
-                        let words: Vec<&str> = content.split_whitespace().collect();
-
-                        // Send chunks with delays to simulate streaming
-                        for (i, word) in words.iter().enumerate() {
-                            let word_with_space = if i < words.len() - 1 {
-                                format!("{} ", word)
-                            } else {
-                                word.to_string()
-                            };
-
-                            let chunk = json!({
-                                "id": id,
-                                "object": "chat.completion.chunk",
-                                "created": created,
-                                "model": model,
-                                "choices": [{
-                                    "index": 0,
-                                    "delta": {
-                                        "content": word_with_space
-                                    },
-                                    "finish_reason": null
-                                }]
-                            });
-
-                            let sse_data = format!(
-                                "data: {}\n\n",
-                                serde_json::to_string(&chunk).unwrap_or_default()
-                            );
-                            if tx.send(Ok(sse_data)).await.is_err() {
-                                return;
-                            }
-
-                            // Add small delay between chunks
-                            tokio::time::sleep(tokio::time::Duration::from_millis(30)).await;
-                        }
-
-                        // Send final chunk with finish_reason and usage
-                        let mut final_chunk = json!({
-                            "id": id,
-                            "object": "chat.completion.chunk",
-                            "created": created,
-                            "model": model,
-                            "choices": [{
-                                "index": 0,
-                                "delta": {},
-                                "finish_reason": first_choice.get("finish_reason").cloned().unwrap_or(json!("stop"))
-                            }]
-                        });
-
-                        // Add usage info if available
-                        if let Some(usage) = json_response.get("usage") {
-                            final_chunk["usage"] = usage.clone();
-                        }
-
-                        let sse_data = format!(
-                            "data: {}\n\n",
-                            serde_json::to_string(&final_chunk).unwrap_or_default()
-                        );
-                        let _ = tx.send(Ok(sse_data)).await;
-                    } else {
-                        // No content found, send the choice as-is
-                        let chunk = json!({
-                            "choices": [first_choice],
-                            "created": json_response.get("created").cloned().unwrap_or(json!(0)),
-                            "id": json_response.get("id").cloned().unwrap_or(json!("unknown")),
-                            "model": json_response.get("model").cloned().unwrap_or(json!("unknown")),
-                            "object": "chat.completion.chunk"
-                        });
-                        let sse_data = format!(
-                            "data: {}\n\n",
-                            serde_json::to_string(&chunk).unwrap_or_default()
-                        );
-                        let _ = tx.send(Ok(sse_data)).await;
-                    }
-                }
-            } else {
-                // Not a standard chat completion, send the whole response as one chunk
-                let sse_data = format!(
-                    "data: {}\n\n",
-                    serde_json::to_string(&json_response).unwrap_or_default()
-                );
-                let _ = tx.send(Ok(sse_data)).await;
+        // Check if it's a chat completion response
+        if let Some(choices) = json_response.get("choices").and_then(|v| v.as_array()) {
+            if !send_choice_chunks(&tx, &json_response, choices).await {
+                return;
             }
         } else {
-            // Failed to parse JSON, send raw data
-            if let Ok(text) = String::from_utf8(response_bytes.to_vec()) {
-                let sse_data = format!("data: {}\n\n", text);
-                let _ = tx.send(Ok(sse_data)).await;
-            }
+            // Not a standard chat completion, send the whole response as one chunk
+            let sse_data = format!(
+                "data: {}\n\n",
+                serde_json::to_string(&json_response).unwrap_or_default()
+            );
+            let _ = tx.send(Ok(sse_data)).await;
         }
 
         // Send the [DONE] marker
@@ -127,8 +56,169 @@ pub fn convert_to_sse_stream(status: StatusCode, response_bytes: bytes::Bytes) -
     });
 
     let stream = ReceiverStream::new(rx);
-    let body = Body::from_stream(stream);
+    sse_response(status, Body::from_stream(stream))
+}
+
+/// Stream every choice word by word, interleaving the choices round by round so each delta
+/// keeps its choice's `index`, then send one final chunk per choice. Choices without text
+/// content (e.g. tool calls) are sent as-is first. Returns false once the client has gone away.
+async fn send_choice_chunks(
+    tx: &tokio::sync::mpsc::Sender<Result<String, std::io::Error>>,
+    json_response: &Value,
+    choices: &[Value],
+) -> bool {
+    // Get metadata
+    let created = json_response.get("created").cloned().unwrap_or(json!(0));
+    let id = json_response.get("id").cloned().unwrap_or(json!("unknown"));
+    let model = json_response
+        .get("model")
+        .cloned()
+        .unwrap_or(json!("unknown"));
+    let object = chunk_object(json_response);
+    let send = |choices: Vec<Value>, usage: Option<&Value>| {
+        let mut chunk = json!({
+            "id": id,
+            "object": object,
+            "created": created,
+            "model": model,
+            "choices": choices
+        });
+        if let Some(usage) = usage {
+            chunk["usage"] = usage.clone();
+        }
+        let sse_data = format!(
+            "data: {}\n\n",
+            serde_json::to_string(&chunk).unwrap_or_default()
+        );
+        async move { tx.send(Ok(sse_data)).await.is_ok() }
+    };
+
+    // Split each choice's content into words for streaming simulation
+    let mut streamed = Vec::new();
+    let mut passthrough = Vec::new();
+    for (position, choice) in choices.iter().enumerate() {
+        let index = choice.get("index").cloned().unwrap_or(json!(position));
+        match choice
+            .get("message")
+            .and_then(|m| m.get("content"))
+            .and_then(|c| c.as_str())
+        {
+            Some(content) => {
+                let words: Vec<&str> = content.split_whitespace().collect();
+                streamed.push((index, choice, words));
+            }
+            None => passthrough.push(choice.clone()),
+        }
+    }
+
+    // No content found, send those choices as-is
+    if !passthrough.is_empty() && !send(passthrough, None).await {
+        return false;
+    }
+    if streamed.is_empty() {
+        return true;
+    }
+
+    // Send chunks with delays to simulate streaming, one delta per choice per round
+    let rounds = streamed
+        .iter()
+        .map(|(_, _, words)| words.len())
+        .max()
+        .unwrap_or(0);
+    for round in 0..rounds {
+        for (index, _, words) in &streamed {
+            let Some(word) = words.get(round) else {
+                continue;
+            };
+            let word_with_space = if round < words.len() - 1 {
+                format!("{} ", word)
+            } else {
+                word.to_string()
+            };
+            let delta = json!({
+                "index": index,
+                "delta": {
+                    "content": word_with_space
+                },
+                "finish_reason": null
+            });
+            if !send(vec![delta], None).await {
+                return false;
+            }
+        }
+
+        // Add small delay between chunks
+        tokio::time::sleep(CHUNK_DELAY).await;
+    }
+
+    // Send a final chunk with finish_reason for each choice, with usage on the last one. An
+    // explicit null from upstream is passed through; only a missing finish_reason becomes "stop".
+    let last = streamed.len() - 1;
+    for (position, (index, choice, _)) in streamed.iter().enumerate() {
+        let finish_reason = match choice.get("finish_reason") {
+            Some(reason) => reason.clone(),
+            None => json!("stop"),
+        };
+        let final_choice = json!({
+            "index": index,
+            "delta": {},
+            "finish_reason": finish_reason
+        });
+        let usage = json_response.get("usage").filter(|_| position == last);
+        if !send(vec![final_choice], usage).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// Chunk `object` for the upstream response's `object`, e.g. `chat.completion` becomes
+/// `chat.completion.chunk`. Other values are kept; a missing one is assumed to be chat.
+fn chunk_object(response: &Value) -> String {
+    match response.get("object").and_then(Value::as_str) {
+        Some(object) if object.ends_with(".completion") => format!("{}.chunk", object),
+        Some(object) => object.to_string(),
+        None => "chat.completion.chunk".to_string(),
+    }
+}
+
+/// Cut a string to at most `max_chars` characters without splitting a multi-byte character
+fn truncate_chars(text: &str, max_chars: usize) -> &str {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => &text[..end],
+        None => text,
+    }
+}
+
+/// Parse the upstream body, retrying once without a UTF-8 BOM and surrounding whitespace
+fn parse_response_json(bytes: &[u8]) -> Option<Value> {
+    serde_json::from_slice::<Value>(bytes).ok().or_else(|| {
+        let text = std::str::from_utf8(bytes).ok()?;
+        serde_json::from_str(text.trim_start_matches('\u{feff}').trim()).ok()
+    })
+}
+
+/// A complete SSE stream holding a single OpenAI-style error event followed by [DONE].
+/// Successful upstream statuses become 502 since the body could not be converted.
+fn sse_error_response(status: StatusCode, message: &str) -> Response {
+    let status = if status.is_success() {
+        StatusCode::BAD_GATEWAY
+    } else {
+        status
+    };
+    let event = json!({
+        "error": {
+            "message": message,
+            "type": "upstream_error",
+            "code": status.as_u16()
+        }
+    });
+    let body = format!("data: {}\n\ndata: [DONE]\n\n", event);
+    sse_response(status, Body::from(body))
+}
 
+/// Wrap an SSE body with the event-stream headers
+pub fn sse_response(status: StatusCode, body: Body) -> Response {
     let mut response = Response::new(body);
     *response.status_mut() = status;
     response
@@ -137,9 +227,28 @@ pub fn convert_to_sse_stream(status: StatusCode, response_bytes: bytes::Bytes) -
     response
         .headers_mut()
         .insert(header::CACHE_CONTROL, "no-cache".parse().unwrap());
-    response
-        .headers_mut()
-        .insert(header::CONNECTION, "keep-alive".parse().unwrap());
+    // No Connection header: it is illegal over HTTP/2 and keep-alive is the HTTP/1.1 default
 
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_chars_keeps_multibyte_characters_whole() {
+        // A two-byte é and a four-byte 🐍 are the 199th and 200th characters, another é follows
+        let text = format!("{}é🐍é", "a".repeat(RAW_BODY_PREVIEW_CHARS - 2));
+        let truncated = truncate_chars(&text, RAW_BODY_PREVIEW_CHARS);
+        assert_eq!(truncated.chars().count(), RAW_BODY_PREVIEW_CHARS);
+        assert!(truncated.ends_with("é🐍"));
+
+        let emoji = "🐍".repeat(RAW_BODY_PREVIEW_CHARS + 1);
+        assert_eq!(
+            truncate_chars(&emoji, RAW_BODY_PREVIEW_CHARS),
+            "🐍".repeat(RAW_BODY_PREVIEW_CHARS)
+        );
+        assert_eq!(truncate_chars("é🐍", RAW_BODY_PREVIEW_CHARS), "é🐍");
+    }
+}
@@ -1,44 +1,79 @@
-use crate::config::Config;
-use crate::proxy::{AppState, proxy_handler};
+use crate::config::{Config, ConfigOverrides, STDIN_CONFIG};
+use crate::error::SnakeError;
+use crate::proxy::{
+    AppState, GATEWAY_HEADER, passthrough_client, proxy_handler, upstream_client_builder,
+};
 use axum::Router;
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde_json::{Value, json};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
-use tracing::info;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Longest a single gateway rotation request may take before it counts as failed
+const ROTATION_REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+/// Time budget per full rotation; requests not sent within the overall budget count as failed
+const ROTATION_DEADLINE: Duration = Duration::from_secs(30);
+/// Message content of the gateway rotation test requests unless `--prompt` is given
+pub const DEFAULT_ROTATION_PROMPT: &str = "Reply with just 'OK'";
 
 /// Test modes
 #[derive(Clone)]
 pub enum TestMode {
     All,
-    Gateway,
+    Gateway(RotationOptions),
     Provider(String),
 }
 
+/// Gateway rotation test settings from the command line
+#[derive(Clone)]
+pub struct RotationOptions {
+    /// Full rotations through the gateways; each gateway serves this many requests
+    pub rotations: u32,
+    pub prompt: String,
+}
+
+impl Default for RotationOptions {
+    fn default() -> Self {
+        Self {
+            rotations: 2,
+            prompt: DEFAULT_ROTATION_PROMPT.to_string(),
+        }
+    }
+}
+
 /// Test the proxy configuration and connection
-pub async fn run_test(config_path: &str, mode: TestMode) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run_test(
+    config_path: &str,
+    overrides: &ConfigOverrides,
+    mode: TestMode,
+) -> Result<(), SnakeError> {
     let mode_desc = match &mode {
         TestMode::All => "all (gateways + providers)",
-        TestMode::Gateway => "gateway rotation only",
+        TestMode::Gateway(_) => "gateway rotation only",
         TestMode::Provider(name) => &format!("provider: {}", name),
     };
     info!("Running proxy test [mode: {}]", mode_desc);
 
     // Check if config file exists
     let path = std::path::Path::new(config_path);
-    if !path.exists() {
-        eprintln!("\n❌ Error: config file not found: {}", config_path);
-        eprintln!("Please create a config.toml file in the project directory");
-        return Err(format!("Missing config file: {}", config_path).into());
+    if config_path != STDIN_CONFIG {
+        if !path.exists() {
+            eprintln!("\n❌ Error: config file not found: {}", config_path);
+            eprintln!("Please create a config.toml file in the project directory");
+            return Err(SnakeError::Config(format!("Missing config file: {}", config_path)));
+        }
+        println!("\n✓ Config file found: {}", config_path);
     }
 
-    println!("\n✓ Config file found: {}", config_path);
-
     // Load config from TOML file
-    let config = Config::from_toml(config_path)?;
+    let config = Config::load(config_path, overrides)?;
 
     // Display configuration
     println!("\n📋 Current Configuration:");
-    println!("  ├─ HOST_PORT: {}", config.listen_addr.split(':').last().unwrap_or("unknown"));
+    let port = config.socket_addr()?.port();
+    println!("  ├─ HOST_PORT: {}", port);
     println!("  ├─ Gateways: {} configured", config.gateways.len());
 
     for (idx, gateway) in config.gateways.iter().enumerate() {
@@ -69,12 +104,10 @@ pub async fn run_test(config_path: &str, mode: TestMode) -> Result<(), Box<dyn s
     if !has_api_key {
         eprintln!("\n⚠️  Warning: No provider API key configured!");
         eprintln!("Please configure at least one provider in config.toml");
-        return Err("No API key configured".into());
+        return Err(SnakeError::Config("No API key configured".to_string()));
     }
 
     println!("\n✓ Configuration validated");
-    // Extract port from listen_addr (format: "0.0.0.0:port")
-    let port = config.listen_addr.split(':').last().unwrap_or("3000");
     let listen_addr = format!("127.0.0.1:{}", port);
 
     // Create HTTP client for testing
@@ -85,17 +118,15 @@ pub async fn run_test(config_path: &str, mode: TestMode) -> Result<(), Box<dyn s
     // Start server in background
     println!("\n🚀 Starting test server on {}...", listen_addr);
 
-    let client = Client::new();
-    let app_state = AppState {
-        client,
-        config: config.clone(),
-    };
+    let client = upstream_client_builder(&config)?.build()?;
+    let passthrough = passthrough_client(&config)?;
+    let app_state = AppState::new(client, passthrough, config.clone());
 
     let app = Router::new()
         .route("/{*path}", axum::routing::any(proxy_handler))
         .with_state(app_state);
 
-    let addr: SocketAddr = listen_addr.parse()?;
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
     let listener = tokio::net::TcpListener::bind(addr).await?;
 
     // Spawn server in background
@@ -116,12 +147,16 @@ pub async fn run_test(config_path: &str, mode: TestMode) -> Result<(), Box<dyn s
     let mut tests_run = 0;
     let mut tests_passed = 0;
     let mut tests_failed = 0;
+    // Providers left out of `TestMode::All`, with the reason, so the summary shows real coverage
+    let mut skipped: Vec<(&str, &str)> = Vec::new();
+    // Outcome of each provider tested in `TestMode::All`, for the summary table
+    let mut results: Vec<ProviderResult> = Vec::new();
 
     // Determine which providers to test based on mode
     match &mode {
-        TestMode::Gateway => {
+        TestMode::Gateway(options) => {
             println!("\n🔄 Testing gateway rotation (will make multiple requests)...\n");
-            return test_gateway_rotation(&config, &test_client, &test_url).await;
+            return test_gateway_rotation(&config, &test_client, &test_url, options).await;
         }
         TestMode::Provider(target_provider) => {
             println!("\n📤 Testing provider: {}...\n", target_provider);
@@ -129,7 +164,7 @@ pub async fn run_test(config_path: &str, mode: TestMode) -> Result<(), Box<dyn s
             // Find the specific provider
             if let Some(provider_config) = config.providers.get(target_provider) {
                 if provider_config.api_keys.is_empty() || provider_config.test_model.is_empty() {
-                    return Err(format!("Provider '{}' has no API keys or test model configured", target_provider).into());
+                    return Err(SnakeError::Config(format!("Provider '{}' has no API keys or test model configured", target_provider)));
                 }
 
                 let test_model = &provider_config.test_model;
@@ -168,15 +203,26 @@ pub async fn run_test(config_path: &str, mode: TestMode) -> Result<(), Box<dyn s
                     }
                 }
             } else {
-                return Err(format!("Provider '{}' not found in config", target_provider).into());
+                return Err(SnakeError::Config(format!("Provider '{}' not found in config", target_provider)));
             }
         }
         TestMode::All => {
             println!("\n📤 Running tests for all configured providers...\n");
 
             for (provider_name, provider_config) in &config.providers {
-                // Skip if no API keys or no test model configured
-                if provider_config.api_keys.is_empty() || provider_config.test_model.is_empty() {
+                let skip_reason = match (
+                    provider_config.api_keys.is_empty(),
+                    provider_config.test_model.is_empty(),
+                ) {
+                    (true, true) => Some("no API keys or test_model configured"),
+                    (true, false) => Some("no API keys configured"),
+                    (false, true) => Some("no test_model configured"),
+                    (false, false) => None,
+                };
+                if let Some(reason) = skip_reason {
+                    warn!("Skipping provider {}: {}", provider_name, reason);
+                    println!("⏭️  Skipping {}: {}\n", provider_name, reason);
+                    skipped.push((provider_name, reason));
                     continue;
                 }
 
@@ -185,6 +231,7 @@ pub async fn run_test(config_path: &str, mode: TestMode) -> Result<(), Box<dyn s
 
                 tests_run += 1;
 
+                let started = Instant::now();
                 let result = test_single_provider(
                     provider_name,
                     test_model,
@@ -192,31 +239,67 @@ pub async fn run_test(config_path: &str, mode: TestMode) -> Result<(), Box<dyn s
                     &test_client,
                     &test_url
                 ).await;
+                let latency = started.elapsed();
 
-                match result {
-                    Ok(_) => tests_passed += 1,
+                let (status, passed) = match result {
+                    Ok(status) if status.is_success() => (status.as_u16().to_string(), true),
+                    Ok(status) => {
+                        println!("❌ Error: HTTP {}", status.as_u16());
+                        (status.as_u16().to_string(), false)
+                    }
                     Err(e) => {
-                        tests_failed += 1;
                         println!("❌ Error: {}", e);
+                        ("error".to_string(), false)
                     }
+                };
+                if passed {
+                    tests_passed += 1;
+                } else {
+                    tests_failed += 1;
                 }
+                results.push(ProviderResult {
+                    provider: provider_name,
+                    model: test_model,
+                    status,
+                    latency,
+                    passed,
+                });
             }
         }
     }
 
     // Print summary (skip for gateway mode as it has its own summary)
-    if !matches!(mode, TestMode::Gateway) {
+    if !matches!(mode, TestMode::Gateway(_)) {
         println!("\n━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
         println!("📊 Test Summary");
         println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+        if !results.is_empty() {
+            results.sort_by(|a, b| a.provider.cmp(b.provider));
+            print_results_table(&results);
+            println!();
+        }
         println!("  Total: {}", tests_run);
         println!("  ✅ Passed: {}", tests_passed);
         println!("  ❌ Failed: {}", tests_failed);
+        if !skipped.is_empty() {
+            skipped.sort();
+            println!("  ⏭️  Skipped: {}", skipped.len());
+            for (provider_name, reason) in &skipped {
+                println!("     └─ {}: {}", provider_name, reason);
+            }
+        }
 
         if tests_failed > 0 {
             println!("\n⚠️  Some tests failed. Please check the error messages above.");
             server_handle.abort();
-            return Err(format!("{} test(s) failed", tests_failed).into());
+            return Err(SnakeError::Upstream(format!("{} test(s) failed", tests_failed)));
+        } else if tests_run == 0 {
+            println!("\n⚠️  No providers were tested. Configure api_keys and test_model to test one.");
+        } else if !skipped.is_empty() {
+            println!(
+                "\n✅ All tests that ran passed, but {} provider(s) were skipped and not tested.",
+                skipped.len()
+            );
         } else {
             println!("\n✅ All tests passed successfully!");
         }
@@ -226,6 +309,75 @@ pub async fn run_test(config_path: &str, mode: TestMode) -> Result<(), Box<dyn s
     Ok(())
 }
 
+/// One provider tested in `TestMode::All`
+struct ProviderResult<'a> {
+    provider: &'a str,
+    model: &'a str,
+    /// HTTP status code, or "error" when no response was read
+    status: String,
+    latency: Duration,
+    passed: bool,
+}
+
+/// Print the per-provider results as a table with aligned columns
+fn print_results_table(results: &[ProviderResult]) {
+    let rows: Vec<[String; 5]> = results
+        .iter()
+        .map(|result| {
+            [
+                result.provider.to_string(),
+                result.model.to_string(),
+                result.status.clone(),
+                format!("{}ms", result.latency.as_millis()),
+                if result.passed { "✅ pass" } else { "❌ fail" }.to_string(),
+            ]
+        })
+        .collect();
+    let header = ["Provider", "Model", "Status", "Latency", "Result"].map(str::to_string);
+    let mut widths = [0; 4];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        // The last column holds an emoji, so it is left unpadded
+        println!(
+            "  {:<w0$}  {:<w1$}  {:<w2$}  {:>w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        );
+    }
+}
+
+/// Build the chat completion request sent to a provider through the proxy
+pub fn provider_request(
+    test_client: &Client,
+    test_url: &str,
+    test_model: &str,
+    api_key: &str,
+) -> reqwest::RequestBuilder {
+    let test_payload = json!({
+        "model": test_model,
+        "messages": [
+            {"role": "user", "content": "Say 'Hello from provider!' in one short sentence."}
+        ]
+    });
+
+    test_client
+        .post(test_url)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key))
+        .json(&test_payload)
+}
+
 /// Test a single provider with a specific API key (shows key index)
 async fn test_single_provider_with_key(
     provider_name: &str,
@@ -241,18 +393,7 @@ async fn test_single_provider_with_key(
         &api_key.chars().rev().take(4).collect::<String>().chars().rev().collect::<String>()
     );
 
-    let test_payload = json!({
-        "model": test_model,
-        "messages": [
-            {"role": "user", "content": "Say 'Hello from provider!' in one short sentence."}
-        ]
-    });
-
-    let request = test_client
-        .post(test_url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&test_payload);
+    let request = provider_request(test_client, test_url, test_model, api_key);
 
     match request.send().await {
         Ok(response) => {
@@ -303,30 +444,19 @@ async fn test_single_provider_with_key(
     }
 }
 
-/// Test a single provider
+/// Test a single provider, returning the HTTP status it answered with
 async fn test_single_provider(
     provider_name: &str,
     test_model: &str,
     api_key: &str,
     test_client: &Client,
     test_url: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<StatusCode, Box<dyn std::error::Error>> {
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!("🧪 Testing {} ({})", provider_name, test_model);
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
 
-    let test_payload = json!({
-        "model": test_model,
-        "messages": [
-            {"role": "user", "content": "Say 'Hello from provider!' in one short sentence."}
-        ]
-    });
-
-    let request = test_client
-        .post(test_url)
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&test_payload);
+    let request = provider_request(test_client, test_url, test_model, api_key);
 
     match request.send().await {
         Ok(response) => {
@@ -348,7 +478,7 @@ async fn test_single_provider(
                             println!("📄 Response: {}", body);
                         }
                         println!();
-                        Ok(())
+                        Ok(status)
                     } else {
                         println!("❌ Status: {} {}", status.as_u16(), status.canonical_reason().unwrap_or("Unknown"));
 
@@ -359,7 +489,7 @@ async fn test_single_provider(
                             println!("📄 Error: {}", body);
                         }
                         println!();
-                        Err(format!("HTTP {}", status.as_u16()).into())
+                        Ok(status)
                     }
                 }
                 Err(e) => {
@@ -382,50 +512,81 @@ async fn test_gateway_rotation(
     config: &Config,
     test_client: &Client,
     test_url: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
+    options: &RotationOptions,
+) -> Result<(), SnakeError> {
     // Find first available provider for testing
     let (provider_name, provider_config) = config.providers.iter()
         .find(|(_, cfg)| !cfg.api_keys.is_empty() && !cfg.test_model.is_empty())
-        .ok_or("No providers configured for testing")?;
+        .ok_or_else(|| SnakeError::Config("No providers configured for testing".to_string()))?;
 
     let api_key = &provider_config.api_keys[0];
     let test_model = &provider_config.test_model;
     let num_gateways = config.gateways.len();
-    let num_requests = num_gateways * 2; // Test 2 full rotations
+    let rotations = options.rotations.max(1) as usize;
+    let num_requests = num_gateways * rotations;
 
-    println!("Testing {} requests to verify {} gateway rotation...", num_requests, num_gateways);
+    println!(
+        "Testing {} requests ({} full rotation(s)) to verify {} gateway rotation...",
+        num_requests, rotations, num_gateways
+    );
     println!("Using provider: {} ({})", provider_name, test_model);
     println!();
 
     let test_payload = json!({
         "model": test_model,
         "messages": [
-            {"role": "user", "content": "Reply with just 'OK'"}
+            {"role": "user", "content": options.prompt}
         ]
     });
 
     let mut success_count = 0;
-    let mut used_gateways = std::collections::HashSet::new();
+    // Requests served per gateway_id, as reported by the proxy's X-Snake-Gateway header
+    let mut served: BTreeMap<String, usize> = BTreeMap::new();
+    let mut timed_out = Vec::new();
+    let budget = ROTATION_DEADLINE * options.rotations.max(1);
+    let deadline = Instant::now() + budget;
 
     for i in 0..num_requests {
         print!("Request {}/{}: ", i + 1, num_requests);
 
-        let response = test_client
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            println!("⏱️  Not sent (overall deadline of {:?} reached)", budget);
+            timed_out.push(i + 1);
+            continue;
+        }
+        let request = test_client
             .post(test_url)
             .header("Content-Type", "application/json")
             .header("Authorization", format!("Bearer {}", api_key))
             .json(&test_payload)
-            .send()
-            .await?;
+            .send();
+        let response = match tokio::time::timeout(ROTATION_REQUEST_TIMEOUT.min(remaining), request)
+            .await
+        {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                println!("❌ Failed ({})", e);
+                continue;
+            }
+            Err(_) => {
+                println!("⏱️  Timed out after {:?}", ROTATION_REQUEST_TIMEOUT.min(remaining));
+                timed_out.push(i + 1);
+                continue;
+            }
+        };
 
         let status = response.status();
         if status.is_success() {
             success_count += 1;
-            println!("✅ OK (HTTP {})", status.as_u16());
-
-            // Track which gateway was used (inferred from rotation)
-            let gateway_idx = i % num_gateways;
-            used_gateways.insert(gateway_idx);
+            let gateway_id = response
+                .headers()
+                .get(GATEWAY_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("unknown")
+                .to_string();
+            println!("✅ OK (HTTP {}, gateway {})", status.as_u16(), gateway_id);
+            *served.entry(gateway_id).or_default() += 1;
         } else {
             println!("❌ Failed (HTTP {})", status.as_u16());
         }
@@ -440,35 +601,62 @@ async fn test_gateway_rotation(
     println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
     println!("  Total Requests: {}", num_requests);
     println!("  Successful: {}", success_count);
+    if !timed_out.is_empty() {
+        let indices: Vec<String> = timed_out.iter().map(|i| i.to_string()).collect();
+        println!("  Timed Out: {} (request {})", timed_out.len(), indices.join(", "));
+    }
     println!("  Gateways Configured: {}", num_gateways);
-    println!("  Gateways Used: {}", used_gateways.len());
+    println!("  Gateways Used: {}", served.len());
+    for (gateway_id, count) in &served {
+        println!("     └─ {}: {} request(s)", gateway_id, count);
+    }
     println!();
 
-    if success_count == num_requests && used_gateways.len() == num_gateways {
+    // Each full rotation serves every gateway once; gateways sharing a gateway_id add up
+    let mut expected: BTreeMap<String, usize> = BTreeMap::new();
+    for gateway in &config.gateways {
+        *expected.entry(gateway.gateway_id.clone()).or_default() += rotations;
+    }
+
+    if success_count < num_requests {
+        Err(SnakeError::Upstream(format!("{} requests failed", num_requests - success_count)))
+    } else if served == expected {
         println!("✅ Gateway rotation working correctly!");
-        println!("   All {} gateways were used in round-robin fashion", num_gateways);
+        println!(
+            "   All {} gateways were used in round-robin fashion over {} rotation(s)",
+            num_gateways, rotations
+        );
         Ok(())
-    } else if success_count < num_requests {
-        Err(format!("{} requests failed", num_requests - success_count).into())
     } else {
-        Err("Gateway rotation may not be working as expected".into())
+        println!(
+            "❌ Expected {} request(s) per gateway, but the distribution above differs",
+            rotations
+        );
+        Err(SnakeError::Upstream(
+            "Gateway rotation is not round-robin: requests were not spread evenly across gateways"
+                .to_string(),
+        ))
     }
 }
 
 /// Mask a string by showing only first and last 4 characters
 fn mask_string(s: &str) -> String {
-    if s.len() <= 8 {
-        "*".repeat(s.len())
-    } else {
-        format!("{}...{}", &s[..4], &s[s.len() - 4..])
-    }
+    mask_middle(s, 4, 4)
 }
 
 /// Mask an API key by showing only first 6 and last 4 characters
 fn mask_api_key(key: &str) -> String {
-    if key.len() <= 10 {
-        "*".repeat(key.len())
+    mask_middle(key, 6, 4)
+}
+
+/// Keep the first `head` and last `tail` characters, counting characters rather than bytes
+fn mask_middle(s: &str, head: usize, tail: usize) -> String {
+    let chars: Vec<char> = s.chars().collect();
+    if chars.len() <= head + tail {
+        "*".repeat(chars.len())
     } else {
-        format!("{}...{}", &key[..6], &key[key.len() - 4..])
+        let start: String = chars[..head].iter().collect();
+        let end: String = chars[chars.len() - tail..].iter().collect();
+        format!("{}...{}", start, end)
     }
 }
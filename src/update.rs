@@ -1,19 +1,86 @@
+use crate::error::SnakeError;
+use crate::service::{DEFAULT_SERVICE_NAME, InstalledService, installed_service};
+use clap::ValueEnum;
+use self_update::update::{Release, ReleaseUpdate};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use tracing::info;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// Name of the release asset listing `sha256sum` output for every binary
+const CHECKSUMS_ASSET: &str = "SHA256SUMS";
+/// How long the restarted service must stay up before it counts as running the new version
+const SERVICE_SETTLE_TIME: Duration = Duration::from_secs(5);
+
+/// Release channel to pull updates from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Channel {
+    /// Only published releases, GitHub prereleases are ignored
+    #[default]
+    Stable,
+    /// Published releases and prereleases
+    Beta,
+}
+
+/// Options controlling how `check_and_update` behaves
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+    /// Skip the confirmation prompt
+    pub skip_confirm: bool,
+    /// GitHub token, falls back to GITHUB_TOKEN when unset
+    pub token: Option<String>,
+    /// Release channel to pick the latest version from
+    pub channel: Channel,
+    /// Only report what would happen, without downloading or installing
+    pub dry_run: bool,
+    /// Install this exact release tag instead of the latest, even if older
+    pub target_version: Option<String>,
+    /// Also run `config check` against this config file with the new binary
+    pub check_config: Option<String>,
+    /// Leave a running snake.service on the old version for the operator to restart
+    pub no_restart: bool,
+    /// Service to restart after installing, as named by `service start --name` (default: snake)
+    pub service_name: Option<String>,
+}
+
+/// Subset of the GitHub release API response used for channel filtering
+#[derive(Debug, Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    draft: bool,
+}
 
 /// Check for updates and install if available
 pub async fn check_and_update(
     version: &str,
     repo_owner: &str,
     repo_name: &str,
-    skip_confirm: bool,
-    token: Option<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
+    options: UpdateOptions,
+) -> Result<(), SnakeError> {
+    let UpdateOptions {
+        skip_confirm,
+        token,
+        channel,
+        dry_run,
+        target_version,
+        check_config,
+        no_restart,
+        service_name,
+    } = options;
+    let service_name = service_name.as_deref().unwrap_or(DEFAULT_SERVICE_NAME);
+    let service = installed_service(service_name)?;
+
     info!("Current version: {}", version);
     info!(
-        "Checking for updates from GitHub repository: {}/{}",
-        repo_owner, repo_name
+        "Checking for updates from GitHub repository: {}/{} (channel: {:?})",
+        repo_owner, repo_name, channel
     );
 
     // Use token from CLI argument, or fall back to GITHUB_TOKEN env var
@@ -43,19 +110,32 @@ pub async fn check_and_update(
             .build()?
     };
 
-    let latest_release = status.get_latest_release()?;
+    let pinned = target_version.is_some();
+    let latest_tag = match target_version {
+        // Release tags are published as v{version}
+        Some(tag) if tag.starts_with('v') => tag,
+        Some(tag) => format!("v{}", tag),
+        None => latest_release_tag(repo_owner, repo_name, channel, &github_token).await?,
+    };
+    // self_update uses blocking reqwest, which must not run directly on the async runtime
+    let latest_release = tokio::task::block_in_place(|| status.get_release_version(&latest_tag))?;
     let latest_version = latest_release.version.trim_start_matches('v');
 
-    info!("Latest version available: {}", latest_version);
+    if pinned {
+        info!("Pinned target version: {}", latest_version);
+    } else {
+        info!("Latest version available: {}", latest_version);
+    }
 
     // Check if versions are exactly the same
-    if version == latest_version {
+    if version == latest_version && !dry_run {
         info!("You are already running the latest version!");
         return Ok(());
     }
 
     // Try to parse and compare versions using semver
-    let needs_update = match (
+    // A pinned version is installed whenever it differs, including downgrades
+    let needs_update = version != latest_version && (pinned || match (
         semver::Version::parse(version),
         semver::Version::parse(latest_version),
     ) {
@@ -67,11 +147,15 @@ pub async fn check_and_update(
             {
                 // Different version numbers - use normal semver comparison
                 latest > current
-            } else {
+            } else if channel == Channel::Beta {
                 // Same major.minor.patch but different pre-release/build metadata
                 // Always offer to update in this case (e.g., 0.0.8 -> 0.0.8-1, 0.0.8-1 -> 0.0.8-2)
                 // This handles hotfix releases properly
                 true
+            } else {
+                // Stable channel follows strict semver, so 0.0.8-1 -> 0.0.8 updates
+                // but 0.0.8 -> 0.0.8-1 does not
+                latest > current
             }
         }
         _ => {
@@ -79,7 +163,20 @@ pub async fn check_and_update(
             info!("Cannot compare versions using semver, will prompt user");
             true
         }
-    };
+    });
+
+    if dry_run {
+        print_dry_run(
+            version,
+            latest_version,
+            pinned,
+            channel,
+            needs_update,
+            no_restart,
+            service.as_ref(),
+        );
+        return Ok(());
+    }
 
     if !needs_update {
         info!(
@@ -96,10 +193,21 @@ pub async fn check_and_update(
 
     // Confirm update if not skipped
     if !skip_confirm {
-        println!(
-            "\nA different version is available: {} -> {}",
-            version, latest_version
+        let is_downgrade = matches!(
+            (semver::Version::parse(version), semver::Version::parse(latest_version)),
+            (Ok(current), Ok(target)) if target < current
         );
+        if is_downgrade {
+            println!(
+                "\n⚠️  Pinned version {} is older than the current version {} (downgrade)",
+                latest_version, version
+            );
+        } else {
+            println!(
+                "\nA different version is available: {} -> {}",
+                version, latest_version
+            );
+        }
         println!("Do you want to update? (y/N): ");
 
         let mut input = String::new();
@@ -111,88 +219,391 @@ pub async fn check_and_update(
         }
     }
 
+    // Keep the running binary so a broken release can be rolled back by hand
+    let binary_path = env::current_exe()?;
+    let backup_path = backup_path(&binary_path);
+    let backup = match fs::copy(&binary_path, &backup_path) {
+        Ok(_) => {
+            info!("Saved the current binary to {}", backup_path.display());
+            Some(backup_path)
+        }
+        Err(e) => {
+            warn!("Failed to back up {}: {}", binary_path.display(), e);
+            None
+        }
+    };
+
     info!("Downloading and installing update...");
-    let status = if let Some(ref token) = github_token {
-        self_update::backends::github::Update::configure()
-            .repo_owner(repo_owner)
-            .repo_name(repo_name)
-            .bin_name("snake")
-            .show_download_progress(true)
-            .current_version(version)
-            .auth_token(token)
-            .build()?
-            .update()?
-    } else {
-        self_update::backends::github::Update::configure()
-            .repo_owner(repo_owner)
-            .repo_name(repo_name)
-            .bin_name("snake")
-            .show_download_progress(true)
-            .current_version(version)
-            .build()?
-            .update()?
+    tokio::task::block_in_place(|| install_release(status.as_ref(), &latest_release, &github_token))?;
+
+    info!("Successfully updated to version: {}", latest_version);
+    println!("\n✓ Update successful! New version: {}", latest_version);
+
+    // Make sure the new binary starts before restarting a service onto it
+    if let Err(e) = verify_binary(&binary_path, latest_version, check_config.as_deref()) {
+        print_rollback(&e, &binary_path, backup.as_deref(), version, None);
+        return Err(SnakeError::Update(format!("New binary failed verification: {}", e)));
+    }
+
+    // Restart an installed service (system or user systemd unit, or launchd) onto the new binary
+    let Some(service) = service else {
+        info!("No {} service detected", service_name);
+        if cfg!(windows) {
+            println!("ℹ️  Windows services are not restarted automatically; if snake runs as one,");
+            println!("   restart it with: snake service restart --name {}", service_name);
+        } else {
+            println!("Please restart the application to use the new version.");
+        }
+        return Ok(());
     };
+    let label = service.label();
+    info!("Detected {}, checking status...", label);
 
-    info!("Successfully updated to version: {}", status.version());
-    println!("\n✓ Update successful! New version: {}", status.version());
-
-    // Check if snake.service exists and is running
-    let service_exists = std::path::Path::new("/etc/systemd/system/snake.service").exists();
-
-    if service_exists {
-        info!("Detected snake.service, checking status...");
-
-        // Check if service is active
-        let status_check = Command::new("systemctl")
-            .arg("is-active")
-            .arg("snake.service")
-            .output();
-
-        match status_check {
-            Ok(output) => {
-                let is_active = output.status.success();
-
-                if is_active {
-                    info!("Service is running, attempting to restart with new version...");
-                    println!("\n🔄 Detected running snake.service, restarting with new version...");
-
-                    // Restart the service
-                    let restart_result = Command::new("systemctl")
-                        .arg("restart")
-                        .arg("snake.service")
-                        .output();
-
-                    match restart_result {
-                        Ok(restart_output) => {
-                            if restart_output.status.success() {
-                                info!("Service restarted successfully");
-                                println!("✓ Service restarted successfully with new version");
-                            } else {
-                                let stderr = String::from_utf8_lossy(&restart_output.stderr);
-                                eprintln!("⚠️  Warning: Failed to restart service: {}", stderr);
-                                eprintln!("Please manually restart with: sudo systemctl restart snake.service");
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("⚠️  Warning: Failed to execute systemctl restart: {}", e);
-                            eprintln!("Please manually restart with: sudo systemctl restart snake.service");
-                        }
+    match service.is_active() {
+        Ok(true) if no_restart => {
+            info!("Service is running, skipping restart (--no-restart)");
+            println!("\nℹ️  {} is still running the previous version (--no-restart)", label);
+            println!("   Restart it when ready with: {}", service.command_hint("restart"));
+        }
+        Ok(true) => {
+            info!("Service is running, attempting to restart with new version...");
+            println!("\n🔄 Detected running {}, restarting with new version...", label);
+            match service.restart() {
+                Ok(()) => {
+                    info!("Service restarted successfully");
+                    println!("✓ Service restarted successfully with new version");
+                    let active = tokio::task::block_in_place(|| verify_service_active(&service));
+                    if let Err(e) = active {
+                        let backup = backup.as_deref();
+                        print_rollback(&e, &binary_path, backup, version, Some(&service));
+                        return Err(SnakeError::Update(format!(
+                            "Service failed after the update: {}",
+                            e
+                        )));
                     }
-                } else {
-                    info!("Service exists but is not running, no restart needed");
-                    println!("ℹ️  snake.service exists but is not running");
-                    println!("   Start it with: sudo systemctl start snake.service");
+                }
+                Err(e) => {
+                    eprintln!("⚠️  Warning: Failed to restart service: {}", e);
+                    eprintln!("Please manually restart with: {}", service.command_hint("restart"));
                 }
             }
-            Err(e) => {
-                info!("Failed to check service status: {}", e);
-                eprintln!("⚠️  Warning: Failed to check service status: {}", e);
-            }
         }
+        Ok(false) => {
+            info!("Service exists but is not running, no restart needed");
+            println!("ℹ️  {} exists but is not running", label);
+            println!("   Start it with: {}", service.command_hint("start"));
+        }
+        Err(e) => {
+            info!("Failed to check service status: {}", e);
+            eprintln!("⚠️  Warning: Failed to check service status: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Where the binary replaced by an update is kept, next to it as `<name>.old`
+fn backup_path(binary_path: &Path) -> PathBuf {
+    let mut name = binary_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".old");
+    binary_path.with_file_name(name)
+}
+
+/// The version token of `--version` output such as `snake 1.2.3`, without a leading `v`
+fn reported_version(output: &str) -> Option<&str> {
+    output
+        .split_whitespace()
+        .nth(1)
+        .map(|version| version.trim_start_matches('v'))
+}
+
+/// Run the installed binary's `--version`, and `config check` when a config is given
+fn verify_binary(
+    binary_path: &Path,
+    expected_version: &str,
+    config_path: Option<&str>,
+) -> Result<(), String> {
+    let output = Command::new(binary_path)
+        .arg("--version")
+        .output()
+        .map_err(|e| format!("failed to run {} --version: {}", binary_path.display(), e))?;
+    let reported = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        return Err(format!(
+            "{} --version exited with {}",
+            binary_path.display(),
+            output.status
+        ));
+    }
+    if reported_version(&reported) != Some(expected_version) {
+        return Err(format!(
+            "{} --version reported '{}', expected {}",
+            binary_path.display(),
+            reported.trim(),
+            expected_version
+        ));
+    }
+    println!("✓ New binary runs: {}", reported.trim());
+
+    if let Some(config_path) = config_path {
+        let output = Command::new(binary_path)
+            .args(["--config", config_path, "config", "check"])
+            .output()
+            .map_err(|e| format!("failed to run config check: {}", e))?;
+        if !output.status.success() {
+            return Err(format!(
+                "config check of {} failed with {}",
+                config_path, output.status
+            ));
+        }
+        println!("✓ New binary accepts {}", config_path);
+    }
+    Ok(())
+}
+
+/// Check that the service is still active a few seconds after the restart
+fn verify_service_active(service: &InstalledService) -> Result<(), String> {
+    println!(
+        "⏳ Waiting {}s to confirm the service stays up...",
+        SERVICE_SETTLE_TIME.as_secs()
+    );
+    std::thread::sleep(SERVICE_SETTLE_TIME);
+    match service.is_active() {
+        Ok(true) => {
+            println!("✓ {} is active on the new version", service.label());
+            Ok(())
+        }
+        Ok(false) => Err(format!("{} is not running after the restart", service.label())),
+        Err(e) => Err(format!("failed to check {}: {}", service.label(), e)),
+    }
+}
+
+/// Explain that the update is broken and how to go back to the previous binary
+fn print_rollback(
+    problem: &str,
+    binary_path: &Path,
+    backup: Option<&Path>,
+    previous_version: &str,
+    service: Option<&InstalledService>,
+) {
+    error!("Post-update verification failed: {}", problem);
+    eprintln!("\n━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    eprintln!("❌ UPDATE VERIFICATION FAILED");
+    eprintln!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+    eprintln!("   {}", problem);
+    eprintln!("\nTo roll back to version {}:", previous_version);
+    match backup {
+        Some(backup) => eprintln!(
+            "   sudo mv {} {}",
+            backup.display(),
+            binary_path.display()
+        ),
+        None => eprintln!(
+            "   Download v{} from the GitHub releases page and replace {}",
+            previous_version,
+            binary_path.display()
+        ),
+    }
+    if let Some(service) = service {
+        eprintln!("   {}", service.command_hint("restart"));
+        eprintln!("\nInspect the failure with: {}", service.logs_hint());
+    }
+}
+
+/// Print the outcome of an update without applying it
+fn print_dry_run(
+    version: &str,
+    latest_version: &str,
+    pinned: bool,
+    channel: Channel,
+    needs_update: bool,
+    no_restart: bool,
+    service: Option<&InstalledService>,
+) {
+    println!("\n📋 Update Dry Run:");
+    println!("  ├─ Current version: {}", version);
+    if pinned {
+        println!("  ├─ Pinned version: {}", latest_version);
     } else {
-        info!("No snake.service detected");
-        println!("Please restart the application to use the new version.");
+        println!("  ├─ Latest version: {} ({:?} channel)", latest_version, channel);
+    }
+
+    if !needs_update {
+        println!("  └─ Update: not needed, nothing would change");
+        return;
     }
+    println!("  ├─ Update: {} -> {} would be installed", version, latest_version);
+
+    // Mirror the service restart detection in check_and_update
+    let Some(service) = service else {
+        println!("  └─ Service: not installed, no restart would happen");
+        return;
+    };
+    let unit = service.label();
+    match service.is_active() {
+        Ok(true) if no_restart => println!(
+            "  └─ Service: {} is running and would be left for a manual restart",
+            unit
+        ),
+        Ok(true) => println!("  └─ Service: {} is running and would be restarted", unit),
+        Ok(false) => println!(
+            "  └─ Service: {} exists but is not running, no restart would happen",
+            unit
+        ),
+        Err(e) => println!("  └─ Service: failed to check {} status: {}", unit, e),
+    }
+}
+
+/// Find the tag of the newest release on the given channel
+async fn latest_release_tag(
+    repo_owner: &str,
+    repo_name: &str,
+    channel: Channel,
+    github_token: &Option<String>,
+) -> Result<String, SnakeError> {
+    let url = format!(
+        "https://api.github.com/repos/{}/{}/releases?per_page=30",
+        repo_owner, repo_name
+    );
+    let github_error =
+        |e: reqwest::Error| SnakeError::Update(format!("GitHub API request failed: {}", e));
+    let client = reqwest::Client::builder()
+        .user_agent(format!("snake/{}", env!("CARGO_PKG_VERSION")))
+        .build()
+        .map_err(github_error)?;
+    let mut request = client
+        .get(&url)
+        .header(reqwest::header::ACCEPT, "application/vnd.github+json");
+    if let Some(token) = github_token {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request.send().await.map_err(github_error)?;
+    if !response.status().is_success() {
+        return Err(SnakeError::Update(format!(
+            "GitHub API request failed with status {} for {}",
+            response.status(),
+            url
+        )));
+    }
+
+    // GitHub returns releases newest first
+    let releases: Vec<GithubRelease> = response.json().await.map_err(github_error)?;
+    releases
+        .into_iter()
+        .filter(|release| !release.draft)
+        .find(|release| channel == Channel::Beta || !release.prerelease)
+        .map(|release| release.tag_name)
+        .ok_or_else(|| {
+            SnakeError::Update(format!("No releases found on the {:?} channel", channel))
+        })
+}
+
+/// Download the release binary, verify it against the release's SHA256SUMS and replace the running executable
+fn install_release(
+    updater: &dyn ReleaseUpdate,
+    release: &Release,
+    github_token: &Option<String>,
+) -> Result<(), SnakeError> {
+    let target = self_update::get_target();
+    let binary_asset = release
+        .asset_for(target, None)
+        .ok_or_else(|| {
+            SnakeError::Update(format!("No release asset found for target: {}", target))
+        })?;
+    let checksums_asset = release
+        .assets
+        .iter()
+        .find(|asset| asset.name == CHECKSUMS_ASSET)
+        .ok_or_else(|| {
+            SnakeError::Update(format!(
+                "Release {} has no {} asset, refusing to install an unverified binary",
+                release.version, CHECKSUMS_ASSET
+            ))
+        })?;
+
+    let mut headers = updater.api_headers(github_token)?;
+    headers.insert(
+        reqwest::header::ACCEPT,
+        reqwest::header::HeaderValue::from_static("application/octet-stream"),
+    );
+
+    let tmp_dir = self_update::TempDir::new()?;
+
+    // Download the checksum list first so a missing entry fails fast
+    let mut checksums = Vec::new();
+    self_update::Download::from_url(&checksums_asset.download_url)
+        .set_headers(headers.clone())
+        .download_to(&mut checksums)?;
+    let checksums = String::from_utf8(checksums)
+        .map_err(|e| {
+            SnakeError::Update(format!("{} is not valid UTF-8: {}", CHECKSUMS_ASSET, e))
+        })?;
+    let expected = find_checksum(&checksums, &binary_asset.name).ok_or_else(|| {
+        SnakeError::Update(format!(
+            "{} has no entry for {}",
+            CHECKSUMS_ASSET, binary_asset.name
+        ))
+    })?;
+
+    info!("Downloading {}", binary_asset.name);
+    let download_path = tmp_dir.path().join(&binary_asset.name);
+    let download_file = fs::File::create(&download_path)?;
+    self_update::Download::from_url(&binary_asset.download_url)
+        .set_headers(headers)
+        .show_progress(true)
+        .download_to(download_file)?;
+
+    let actual = sha256_file(&download_path)?;
+    if !actual.eq_ignore_ascii_case(&expected) {
+        eprintln!("\n❌ Checksum mismatch for {}", binary_asset.name);
+        eprintln!("   Expected: {}", expected);
+        eprintln!("   Actual:   {}", actual);
+        return Err(SnakeError::Update(
+            "Downloaded binary failed SHA-256 verification, update aborted".to_string(),
+        ));
+    }
+    info!("✓ SHA-256 checksum verified: {}", actual);
+    println!("✓ SHA-256 checksum verified: {}", actual);
+
+    // Release assets are either a plain binary or a tarball containing the binary
+    let bin_name = updater.bin_name();
+    self_update::Extract::from_source(&download_path).extract_file(tmp_dir.path(), &bin_name)?;
+    self_update::self_replace::self_replace(tmp_dir.path().join(&bin_name))?;
 
     Ok(())
 }
+
+/// Find the expected hash for `file_name` in `sha256sum` formatted output
+fn find_checksum(checksums: &str, file_name: &str) -> Option<String> {
+    checksums.lines().find_map(|line| {
+        let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+        // sha256sum prefixes the name with '*' in binary mode
+        let name = name.trim_start().trim_start_matches('*');
+        (name == file_name).then(|| hash.to_string())
+    })
+}
+
+/// Compute the lowercase hex SHA-256 digest of a file
+fn sha256_file(path: &Path) -> Result<String, SnakeError> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reported_version_matches_the_whole_token() {
+        assert_eq!(reported_version("snake 1.2.3\n"), Some("1.2.3"));
+        assert_eq!(reported_version("snake v1.2.3"), Some("1.2.3"));
+        // A substring match would accept all of these as 1.2.3
+        assert_ne!(reported_version("snake 11.2.3"), Some("1.2.3"));
+        assert_ne!(reported_version("snake 1.2.30"), Some("1.2.3"));
+        assert_ne!(reported_version("snake 1.2.3-rc.1"), Some("1.2.3"));
+        assert_eq!(reported_version(""), None);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::config::{Config, ConfigOverrides, STDIN_CONFIG};
+use crate::error::SnakeError;
+use crate::proxy::AppState;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Quiet period after the last change before reloading, so a save in progress isn't read half-written
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Reload the config into `state` whenever the file changes on disk (`serve --watch`).
+/// An invalid file is logged and the previous config stays in use.
+pub fn spawn_config_watcher(
+    state: AppState,
+    path: String,
+    overrides: ConfigOverrides,
+) -> Result<(), SnakeError> {
+    if path == STDIN_CONFIG {
+        return Err(SnakeError::Config(
+            "--watch needs a config file; a config read from stdin cannot be reloaded".to_string(),
+        ));
+    }
+    let config_path = PathBuf::from(&path);
+    let file_name = config_path
+        .file_name()
+        .map(|name| name.to_os_string())
+        .ok_or_else(|| SnakeError::Config(format!("Cannot watch {}: not a file path", path)))?;
+    // Watch the directory, since many editors save by renaming a new file over the old one
+    let directory = match config_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let (changes, mut receiver) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |result: notify::Result<Event>| match result {
+            Ok(event) => {
+                let touches_config = event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == Some(file_name.as_os_str()));
+                if touches_config && !matches!(event.kind, EventKind::Access(_)) {
+                    let _ = changes.send(());
+                }
+            }
+            Err(e) => error!("Config watcher error: {}", e),
+        })
+        .map_err(|e| io::Error::other(format!("Failed to start config watcher: {}", e)))?;
+    watcher
+        .watch(&directory, RecursiveMode::NonRecursive)
+        .map_err(|e| io::Error::other(format!("Failed to watch {}: {}", directory.display(), e)))?;
+    info!("Watching {} for changes", path);
+
+    tokio::spawn(async move {
+        // Dropping the watcher stops the events, so the task owns it
+        let _watcher = watcher;
+        while receiver.recv().await.is_some() {
+            while let Ok(Some(())) = tokio::time::timeout(DEBOUNCE, receiver.recv()).await {}
+            reload(&state, &path, &overrides);
+        }
+    });
+    Ok(())
+}
+
+fn reload(state: &AppState, path: &str, overrides: &ConfigOverrides) {
+    let previous = state.current().config;
+    let mut config = match Config::load(path, overrides) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Config reload failed, keeping the previous config: {}", e);
+            return;
+        }
+    };
+    config.inherit_runtime_state(&previous);
+
+    let restart_only = config.restart_only_changes(&previous);
+    if !restart_only.is_empty() {
+        warn!(
+            "Config changes to {} only take effect after a restart",
+            restart_only.join(", ")
+        );
+    }
+    info!(
+        "✓ Config reloaded from {} ({} gateway(s))",
+        path,
+        config.gateways.len()
+    );
+    state.replace_config(config);
+}
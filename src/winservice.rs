@@ -0,0 +1,355 @@
+use crate::error::SnakeError;
+use crate::service::InstallOptions;
+use std::ffi::{OsStr, OsString};
+use std::sync::OnceLock;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::{error, info};
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+
+const DEFAULT_DISPLAY_NAME: &str = "Snake - the API proxy";
+/// How long stop and restart wait for the service to reach the stopped state
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Win32 error for an SCM call made without administrator rights
+const ERROR_ACCESS_DENIED: i32 = 5;
+/// Win32 error for a service that isn't installed
+const ERROR_SERVICE_DOES_NOT_EXIST: i32 = 1060;
+
+/// Set once the process runs under the SCM, used to report the stopped state on exit
+static STATUS_HANDLE: OnceLock<ServiceStatusHandle> = OnceLock::new();
+/// Tells the SCM thread whether the dispatcher connected
+static STARTED: OnceLock<mpsc::Sender<bool>> = OnceLock::new();
+/// Wakes `stop_requested` when the SCM sends Stop or Shutdown
+static STOP: Notify = Notify::const_new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Turn a windows-service error into a `SnakeError`, with a hint for missing admin rights
+fn scm_error(action: &str, e: windows_service::Error) -> SnakeError {
+    let os_error = match &e {
+        windows_service::Error::Winapi(io_error) => io_error.raw_os_error(),
+        _ => None,
+    };
+    match os_error {
+        Some(ERROR_ACCESS_DENIED) => SnakeError::Service(format!(
+            "Failed to {}: access denied (run from an elevated Administrator prompt)",
+            action
+        )),
+        _ => SnakeError::Service(format!("Failed to {}: {}", action, e)),
+    }
+}
+
+fn status(state: ServiceState, controls_accepted: ServiceControlAccept) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
+
+/// Register the service with the SCM and start it
+pub fn install_service(
+    config_path: &str,
+    name: &str,
+    options: InstallOptions,
+) -> Result<(), SnakeError> {
+    info!("Installing snake as Windows service {}...", name);
+
+    let binary_path = std::env::current_exe()?;
+    let display_name = options
+        .description
+        .unwrap_or_else(|| DEFAULT_DISPLAY_NAME.to_string());
+    let mut launch_arguments = vec![OsString::from("--config"), OsString::from(config_path)];
+    if let Some(instance) = &options.instance {
+        launch_arguments.push(OsString::from("--instance"));
+        launch_arguments.push(OsString::from(instance));
+    }
+    if options.allow_duplicate_gateways {
+        launch_arguments.push(OsString::from("--allow-duplicate-gateways"));
+    }
+    launch_arguments.push(OsString::from("serve"));
+
+    println!("📋 Service Configuration:");
+    println!("  ├─ Name: {}", name);
+    println!("  ├─ Display Name: {}", display_name);
+    println!("  ├─ Binary: {}", binary_path.display());
+    println!("  ├─ Config: {}", config_path);
+    if let Some(instance) = &options.instance {
+        println!("  ├─ Instance: {}", instance);
+    }
+    println!("  └─ Account: LocalSystem");
+    let unsupported = options.user.is_some()
+        || options.env_file.is_some()
+        || options.restart_sec.is_some()
+        || options.memory_max.is_some()
+        || options.tasks_max.is_some();
+    if unsupported {
+        eprintln!(
+            "⚠️  --user, --env-file, --restart-sec, --memory-max and --tasks-max are not \
+             supported for Windows services, ignoring them"
+        );
+    }
+
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|e| scm_error("connect to the Service Control Manager", e))?;
+
+    let info = ServiceInfo {
+        name: OsString::from(name),
+        display_name: OsString::from(&display_name),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: binary_path,
+        launch_arguments,
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+
+    println!("\n📝 Registering service...");
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)
+        .map_err(|e| scm_error("create the service", e))?;
+    service
+        .set_description(&display_name)
+        .map_err(|e| scm_error("set the service description", e))?;
+    println!("✓ Service registered: {}", name);
+
+    println!("\n🚀 Starting service...");
+    service
+        .start(&[] as &[&OsStr])
+        .map_err(|e| scm_error("start the service", e))?;
+    println!("✓ Service started");
+
+    println!(
+        "\n✅ Snake service {} installed and started successfully!",
+        name
+    );
+    println!("\nUseful commands (from an elevated prompt):");
+    println!(
+        "  snake service status --name {}    - Check service status",
+        name
+    );
+    println!(
+        "  snake service restart --name {}   - Restart service",
+        name
+    );
+    println!(
+        "  snake service stop --name {}      - Stop and remove service",
+        name
+    );
+
+    Ok(())
+}
+
+/// Wait until the service reports the stopped state
+fn wait_for_stop(service: &windows_service::service::Service) -> Result<(), SnakeError> {
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    loop {
+        let state = service
+            .query_status()
+            .map_err(|e| scm_error("query the service status", e))?
+            .current_state;
+        if state == ServiceState::Stopped {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            return Err(SnakeError::Service(format!(
+                "Service did not stop within {}s",
+                STOP_TIMEOUT.as_secs()
+            )));
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// Stop the service if it is running and remove it from the SCM
+pub fn uninstall_service(name: &str) -> Result<(), SnakeError> {
+    info!("Uninstalling snake Windows service {}...", name);
+
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| scm_error("connect to the Service Control Manager", e))?;
+    let access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE;
+    let service = match manager.open_service(name, access) {
+        Ok(service) => service,
+        Err(windows_service::Error::Winapi(e))
+            if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST) =>
+        {
+            eprintln!("⚠️  Service not found: {}", name);
+            eprintln!("Service may not be installed or already removed.");
+            return Ok(());
+        }
+        Err(e) => return Err(scm_error("open the service", e)),
+    };
+
+    println!("🛑 Stopping {}...", name);
+    let state = service
+        .query_status()
+        .map_err(|e| scm_error("query the service status", e))?
+        .current_state;
+    if state != ServiceState::Stopped {
+        service
+            .stop()
+            .map_err(|e| scm_error("stop the service", e))?;
+        wait_for_stop(&service)?;
+    }
+    println!("✓ Service stopped");
+
+    println!("\n🗑️  Removing service...");
+    service
+        .delete()
+        .map_err(|e| scm_error("delete the service", e))?;
+    println!("✓ Service removed: {}", name);
+
+    println!(
+        "\n✅ Snake service {} stopped and uninstalled successfully!",
+        name
+    );
+
+    Ok(())
+}
+
+/// Show the service state reported by the SCM
+pub fn service_status(name: &str) -> Result<(), SnakeError> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| scm_error("connect to the Service Control Manager", e))?;
+    let service = match manager.open_service(name, ServiceAccess::QUERY_STATUS) {
+        Ok(service) => service,
+        Err(windows_service::Error::Winapi(e))
+            if e.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST) =>
+        {
+            println!("⚠️  Service not found: {}", name);
+            println!("Install it with: snake service start --name {}", name);
+            return Ok(());
+        }
+        Err(e) => return Err(scm_error("open the service", e)),
+    };
+    let status = service
+        .query_status()
+        .map_err(|e| scm_error("query the service status", e))?;
+
+    println!("📊 Service Status:");
+    println!("  ├─ Name: {}", name);
+    println!("  ├─ State: {:?}", status.current_state);
+    match status.process_id {
+        Some(pid) => println!("  └─ Process ID: {}", pid),
+        None => println!("  └─ Process ID: none"),
+    }
+    if status.current_state == ServiceState::Running {
+        println!("✅ {} is running", name);
+    } else {
+        println!("⚠️  {} is not running", name);
+        println!("Start it with: sc start {}", name);
+    }
+
+    Ok(())
+}
+
+/// Stop the service and start it again
+pub fn restart_service(name: &str) -> Result<(), SnakeError> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|e| scm_error("connect to the Service Control Manager", e))?;
+    let access = ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::START;
+    let service = manager
+        .open_service(name, access)
+        .map_err(|e| scm_error("open the service", e))?;
+
+    println!("🔄 Restarting {}...", name);
+    let state = service
+        .query_status()
+        .map_err(|e| scm_error("query the service status", e))?
+        .current_state;
+    if state != ServiceState::Stopped {
+        service
+            .stop()
+            .map_err(|e| scm_error("stop the service", e))?;
+        wait_for_stop(&service)?;
+    }
+    service
+        .start(&[] as &[&OsStr])
+        .map_err(|e| scm_error("start the service", e))?;
+    println!("✓ Service restarted");
+
+    Ok(())
+}
+
+/// Connect to the SCM when the process was started as a service, running the dispatcher on
+/// its own thread. From a console the dispatcher fails right away and this returns false.
+pub fn connect_to_scm() -> bool {
+    let (started, connected) = mpsc::channel();
+    if STARTED.set(started.clone()).is_err() {
+        return STATUS_HANDLE.get().is_some();
+    }
+    std::thread::spawn(move || {
+        // The name is ignored for own-process services; a console launch fails here
+        if service_dispatcher::start("snake", ffi_service_main).is_err() {
+            let _ = started.send(false);
+        }
+    });
+    connected.recv().unwrap_or(false)
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    let handler = |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            STOP.notify_one();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    };
+    let started = STARTED.get();
+    let handle = match service_control_handler::register("snake", handler) {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Failed to register the service control handler: {}", e);
+            if let Some(started) = started {
+                let _ = started.send(false);
+            }
+            return;
+        }
+    };
+    let accepted = ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN;
+    if let Err(e) = handle.set_service_status(status(ServiceState::Running, accepted)) {
+        error!("Failed to report the running state to the SCM: {}", e);
+    }
+    let _ = STATUS_HANDLE.set(handle);
+    info!("Running as a Windows service");
+    if let Some(started) = started {
+        let _ = started.send(true);
+    }
+}
+
+/// Resolves when the SCM asks the service to stop; never resolves from a console
+pub async fn stop_requested() {
+    if STATUS_HANDLE.get().is_none() {
+        return std::future::pending().await;
+    }
+    STOP.notified().await;
+}
+
+/// Tell the SCM the service has stopped, once the server has shut down
+pub fn report_stopped() {
+    if let Some(handle) = STATUS_HANDLE.get()
+        && let Err(e) =
+            handle.set_service_status(status(ServiceState::Stopped, ServiceControlAccept::empty()))
+    {
+        error!("Failed to report the stopped state to the SCM: {}", e);
+    }
+}